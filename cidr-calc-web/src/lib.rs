@@ -2,20 +2,25 @@ mod utils;
 
 use cidr_calculator::eval::Value;
 use wasm_bindgen::prelude::*;
-use cidr_calculator::parser::parse_single;
+use cidr_calculator::parser::{parse_single_with, ParseOptions};
 use cidr_calculator::eval::eval_stmt;
 use cidr_calculator::eval::Scope;
-use cidr_calculator::eval::format;
+use cidr_calculator::eval::{format, truncation_warning};
 
 #[wasm_bindgen]
 #[derive(Default)]
 pub struct EvalState {
-    scope: Scope
+    scope: Scope,
+    warnings: Vec<String>,
 }
 
 #[wasm_bindgen]
 pub fn create_state() -> EvalState {
-    Default::default()
+    utils::set_panic_hook();
+    EvalState {
+        scope: Scope::prelude(),
+        warnings: Vec::new(),
+    }
 }
 
 #[wasm_bindgen]
@@ -23,10 +28,20 @@ pub fn print_scope(state: &EvalState) -> Vec<String> {
     state.scope.keys().map(|e| e.to_owned()).collect()
 }
 
+// Warnings from previous evaluations, e.g. prefixes with host bits set
+#[wasm_bindgen]
+pub fn take_warnings(state: &mut EvalState) -> Vec<String> {
+    std::mem::take(&mut state.warnings)
+}
+
 #[wasm_bindgen]
 pub fn eval_input(state: &mut EvalState, input: String) -> Result<Vec<String>, String> {
-    let stmt = parse_single(&input).map_err(|e| e.to_string())?;
-    let (v, s) = eval_stmt(&stmt, state.scope.clone()).map_err(|e| e.to_string())?;
+    let mut warnings = Vec::new();
+    let stmt = parse_single_with(&input, ParseOptions::default(), &mut warnings).map_err(|e| e.to_string())?;
+    state.warnings.extend(warnings.iter().map(|w| w.to_string()));
+    let evaled = eval_stmt(&stmt, state.scope.clone());
+    state.warnings.extend(state.scope.take_warnings().iter().map(|w| w.to_string()));
+    let (v, s) = evaled.map_err(|e| e.to_string())?;
     state.scope = s;
 
     match v {
@@ -34,6 +49,7 @@ pub fn eval_input(state: &mut EvalState, input: String) -> Result<Vec<String>, S
             Ok(vec![])
         }
         v => {
+            state.warnings.extend(truncation_warning(&v).map(|w| w.to_string()));
             Ok(format(&v).collect())
         }
     }
@@ -1,34 +1,58 @@
+use std::fmt;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Family {
+    V4,
+    V6,
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct V4(pub u32, pub u8);
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct V6(pub u128, pub u8);
 
-impl ToString for V4 {
-    fn to_string(&self) -> String {
+impl V4 {
+    // Inclusive first and last address covered by this prefix
+    pub fn range(&self) -> (u32, u32) {
+        let host_mask = u32::MAX.checked_shr(self.1 as u32).unwrap_or(0);
+        (self.0 & !host_mask, self.0 | host_mask)
+    }
+}
+
+impl V6 {
+    // Inclusive first and last address covered by this prefix
+    pub fn range(&self) -> (u128, u128) {
+        let host_mask = u128::MAX.checked_shr(self.1 as u32).unwrap_or(0);
+        (self.0 & !host_mask, self.0 | host_mask)
+    }
+}
+
+impl fmt::Display for V4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Mask end zeros
         if self.1 == 0 {
-            return "0.0.0.0/0".to_owned();
+            return write!(f, "0.0.0.0/0");
         }
 
         let masked = self.0 & !((1u32 << (32 - self.1)) - 1);
-        format!("{}/{}", masked.to_be_bytes().map(|e| e.to_string()).join("."), self.1)
+        write!(f, "{}/{}", masked.to_be_bytes().map(|e| e.to_string()).join("."), self.1)
     }
 }
 
-impl ToString for V6 {
-    fn to_string(&self) -> String {
+impl fmt::Display for V6 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.1 == 0 {
-            return "::/0".to_owned();
+            return write!(f, "::/0");
         }
 
         let masked = self.0 & !((1u128 << (128 - self.1)) - 1);
         let mut segs = <[Option::<String>; 8]>::default();
 
-        for grpidx in 0..8 {
+        for (grpidx, seg) in segs.iter_mut().enumerate() {
             let grp = (masked >> ((8 - grpidx - 1) * 16)) & 0xFFFFu128;
             if grp != 0 {
-                segs[grpidx] = Some(format!("{:x}", grp));
+                *seg = Some(format!("{:x}", grp));
             }
         }
 
@@ -50,14 +74,53 @@ impl ToString for V6 {
         // Format
         if zero_lengths_max.0 == 0 {
             // No zero segments
-            format!("{}/{}", segs.map(Option::unwrap).join(":"), self.1)
+            write!(f, "{}/{}", segs.map(Option::unwrap).join(":"), self.1)
         } else {
             let seg_start = zero_lengths_max.1 + 1 - zero_lengths_max.0;
             let seg_head = &segs[0..seg_start];
             let seg_tail= &segs[(zero_lengths_max.1 + 1)..8];
             let head = seg_head.iter().map(|e| e.as_ref().map(|i| i.as_str()).unwrap_or("0")).collect::<Vec<_>>().join(":");
             let tail = seg_tail.iter().map(|e| e.as_ref().map(|i| i.as_str()).unwrap_or("0")).collect::<Vec<_>>().join(":");
-            format!("{}::{}/{}", head, tail, self.1)
+            write!(f, "{}::{}/{}", head, tail, self.1)
+        }
+    }
+}
+// Exact integer, wide enough for the size of the whole v6 space (2^128)
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Number {
+    pub negative: bool,
+    // Magnitude is carry * 2^128 + low
+    pub carry: bool,
+    pub low: u128,
+}
+
+impl From<u128> for Number {
+    fn from(value: u128) -> Self {
+        Self { negative: false, carry: false, low: value }
+    }
+}
+
+impl Number {
+    // None if negative or beyond u128
+    pub fn to_u128(&self) -> Option<u128> {
+        (!self.negative && !self.carry).then_some(self.low)
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+
+        if !self.carry {
+            return write!(f, "{}", self.low);
         }
+
+        // Split into base 10^19 digits, 2^128 = 34028236692093846346 * 10^19 + 3374607431768211456
+        const BASE: u128 = 10_000_000_000_000_000_000;
+        let lo = 3374607431768211456 + self.low % BASE;
+        let hi = 34028236692093846346 + self.low / BASE + lo / BASE;
+        write!(f, "{}{:019}", hi, lo % BASE)
     }
-}
\ No newline at end of file
+}
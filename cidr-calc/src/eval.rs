@@ -1,11 +1,12 @@
-use std::{rc::Rc, collections::VecDeque, iter};
+use std::{borrow::Cow, rc::Rc, cell::RefCell, collections::VecDeque, iter, path::{Path, PathBuf}};
 
 use anyhow::anyhow;
 
-use crate::{parser::{Expr, Atomic, Stmt}, data::{V4, V6}};
+use crate::{parser::{Expr, Atomic, Stmt, Predicate, ParseOptions, Warning, parse, parse_with, parse_list_entry}, data::{V4, V6, Family, Number}};
 
-#[derive(Clone, Debug)]
-struct SetNode<const MAX_DEPTH: usize> {
+// Canonical trees are unique, so structural equality is set equality
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetNode<const MAX_DEPTH: usize> {
     depth: usize,
     covered: bool,
     left: Option<Rc<SetNode<MAX_DEPTH>>>,
@@ -42,6 +43,65 @@ fn subtract_option<const MAX_DEPTH: usize>(
     }
 }
 
+fn intersect_option<const MAX_DEPTH: usize>(
+    lhs: &Option<Rc<SetNode<MAX_DEPTH>>>,
+    rhs: &Option<Rc<SetNode<MAX_DEPTH>>>,
+) -> Option<Rc<SetNode<MAX_DEPTH>>> {
+    match (lhs, rhs) {
+        (None, _) | (_, None) => None,
+        (Some(l), Some(r)) => {
+            let raw = l.intersect(r.as_ref());
+            // Disjoint subtrees result in empty set
+            if raw.is_empty() {
+                None
+            } else {
+                Some(Rc::new(raw))
+            }
+        }
+    }
+}
+
+fn symmetric_difference_option<const MAX_DEPTH: usize>(
+    lhs: &Option<Rc<SetNode<MAX_DEPTH>>>,
+    rhs: &Option<Rc<SetNode<MAX_DEPTH>>>,
+) -> Option<Rc<SetNode<MAX_DEPTH>>> {
+    match (lhs, rhs) {
+        (None, r) => r.clone(),
+        (l @ Some(_), None) => l.clone(),
+        (Some(l), Some(r)) => {
+            let raw = l.symmetric_difference(r.as_ref());
+            // Identical subtrees cancel out
+            if raw.is_empty() {
+                None
+            } else {
+                Some(Rc::new(raw))
+            }
+        }
+    }
+}
+
+fn complement_option<const MAX_DEPTH: usize>(
+    node: &Option<Rc<SetNode<MAX_DEPTH>>>,
+    depth: usize,
+) -> Option<Rc<SetNode<MAX_DEPTH>>> {
+    match node {
+        None => Some(Rc::new(SetNode {
+            depth,
+            covered: true,
+            left: None,
+            right: None,
+        })),
+        Some(n) => {
+            let raw = n.complement();
+            if raw.is_empty() {
+                None
+            } else {
+                Some(Rc::new(raw))
+            }
+        }
+    }
+}
+
 impl<const MAX_DEPTH: usize> SetNode<MAX_DEPTH> {
     pub fn union(&self, ano: &SetNode<MAX_DEPTH>) -> SetNode<MAX_DEPTH> {
         assert_eq!(ano.depth, self.depth);
@@ -61,8 +121,8 @@ impl<const MAX_DEPTH: usize> SetNode<MAX_DEPTH> {
         assert_ne!(self.depth, MAX_DEPTH);
         let left = union_option(&self.left, &ano.left);
         let right = union_option(&self.right, &ano.right);
-        let covered = left.as_ref().map_or(false, |i| i.covered)
-            && right.as_ref().map_or(false, |i| i.covered);
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
 
         if covered {
             return SetNode {
@@ -113,8 +173,8 @@ impl<const MAX_DEPTH: usize> SetNode<MAX_DEPTH> {
         assert_ne!(self.depth, MAX_DEPTH);
         let left = subtract_option(left_ref, &ano.left);
         let right = subtract_option(right_ref, &ano.right);
-        let covered = left.as_ref().map_or(false, |i| i.covered)
-            && right.as_ref().map_or(false, |i| i.covered);
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
 
         if covered {
             return SetNode {
@@ -133,229 +193,1369 @@ impl<const MAX_DEPTH: usize> SetNode<MAX_DEPTH> {
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        !self.covered && self.left.is_none() && self.right.is_none()
-    }
-
-    pub fn is_canonical(&self) -> bool {
-        todo!()
-    }
-}
+    pub fn intersect(&self, ano: &SetNode<MAX_DEPTH>) -> SetNode<MAX_DEPTH> {
+        assert_eq!(ano.depth, self.depth);
+        if self.covered {
+            return ano.clone();
+        }
 
-#[derive(Clone)]
-struct SetWalkerFrame<'a, const MAX_DEPTH: usize> {
-    node: &'a SetNode<MAX_DEPTH>,
-    inspected_branches: usize, // Inspected branches. Now always 0 / 1 / 2
-}
+        if ano.covered {
+            return self.clone();
+        }
 
-// Iterator, stack top always pointing at a covered node, except in the terminal state, where the stack is empty
-struct SetWalker<'a, const MAX_DEPTH: usize> {
-    stack: VecDeque<SetWalkerFrame<'a, MAX_DEPTH>>
-}
+        if self.is_empty() || ano.is_empty() {
+            return SetNode {
+                depth: self.depth,
+                covered: false,
+                left: None,
+                right: None,
+            };
+        }
 
-impl<'a, const MAX_DEPTH: usize> SetWalker<'a, MAX_DEPTH> {
-    pub fn new(n: &'a SetNode<MAX_DEPTH>) -> Self {
-        let mut s = Self {
-            stack: VecDeque::with_capacity(MAX_DEPTH + 1),
-        };
-        s.stack.push_back(SetWalkerFrame { node: n, inspected_branches: 0 });
-        s
-    }
+        assert_ne!(self.depth, MAX_DEPTH);
+        let left = intersect_option(&self.left, &ano.left);
+        let right = intersect_option(&self.right, &ano.right);
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
 
-    fn step(&mut self) {
-        if self.stack.is_empty() {
-            assert!(self.stack.is_empty());
-            return;
+        if covered {
+            return SetNode {
+                depth: self.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
         }
 
-        let top_node = self.stack.back_mut().unwrap().node;
-        let top_inspected = &mut self.stack.back_mut().unwrap().inspected_branches;
-        match *top_inspected {
-            0 => {
-                *top_inspected += 1;
-                if let Some(ref cur) = top_node.left {
-                    self.stack.push_back(SetWalkerFrame { node: cur.as_ref(), inspected_branches: 0 })
-                }
-            },
-            1 => {
-                *top_inspected += 1;
-                if let Some(ref cur) = top_node.right {
-                    self.stack.push_back(SetWalkerFrame { node: cur.as_ref(), inspected_branches: 0 })
-                }
-            },
-            2 => {
-                self.stack.pop_back();
-            },
-            _ => unreachable!()
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left,
+            right,
         }
     }
-}
 
-impl<'a, const MAX_DEPTH: usize> Iterator for SetWalker<'a, MAX_DEPTH> {
-    type Item = (u128, usize); // TODO: high percision?
+    pub fn symmetric_difference(&self, ano: &SetNode<MAX_DEPTH>) -> SetNode<MAX_DEPTH> {
+        assert_eq!(ano.depth, self.depth);
+        if self.is_empty() {
+            return ano.clone();
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        while match self.stack.back() {
-            None => false,
-            Some(inner) => !inner.node.covered || inner.inspected_branches > 0
-        } {
-            self.step();
+        if ano.is_empty() {
+            return self.clone();
         }
 
-        if self.stack.is_empty() {
-            return None;
+        if self.covered && ano.covered {
+            return SetNode {
+                depth: self.depth,
+                covered: false,
+                left: None,
+                right: None,
+            };
         }
 
-        // Arrived at a covered node
-        assert_eq!(self.stack.back().map(|e| e.node.covered), Some(true));
+        // Exactly one side is covered here, expand it into two full halves
+        let full = Some(Rc::new(SetNode {
+            depth: self.depth + 1,
+            covered: true,
+            left: None,
+            right: None,
+        }));
+        let (self_left, self_right) = if self.covered { (&full, &full) } else { (&self.left, &self.right) };
+        let (ano_left, ano_right) = if ano.covered { (&full, &full) } else { (&ano.left, &ano.right) };
 
-        // Serialize stack
-        let mut addr = 0u128;
-        let mut len = 0;
-        for elem in &self.stack {
-            if elem.inspected_branches == 0 {
-                // Last one
-                break;
-            }
-            addr <<= 1;
-            addr |= (elem.inspected_branches - 1) as u128;
-            len += 1;
+        assert_ne!(self.depth, MAX_DEPTH);
+        let left = symmetric_difference_option(self_left, ano_left);
+        let right = symmetric_difference_option(self_right, ano_right);
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
+
+        if covered {
+            return SetNode {
+                depth: self.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
         }
 
-        if len != 0 { // Avoid UB
-            addr <<= MAX_DEPTH - len;
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left,
+            right,
         }
+    }
 
-        self.step();
+    // Complement within the subtree rooted at this node, i.e. the whole address family for the root
+    pub fn complement(&self) -> SetNode<MAX_DEPTH> {
+        if self.is_empty() || self.covered {
+            return SetNode {
+                depth: self.depth,
+                covered: !self.covered,
+                left: None,
+                right: None,
+            };
+        }
 
-        return Some((addr, len));
-    }
-}
+        assert_ne!(self.depth, MAX_DEPTH);
+        let left = complement_option(&self.left, self.depth + 1);
+        let right = complement_option(&self.right, self.depth + 1);
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
 
-#[derive(Clone, Debug)]
-pub enum Value {
-    Unit,
-    V4Set(SetNode<32>),
-    V6Set(SetNode<128>),
-}
+        if covered {
+            return SetNode {
+                depth: self.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
+        }
 
-impl Value {
-    fn is_same_len(&self, ano: &Value) -> bool {
-        match (self, ano) {
-            (Value::V4Set(_), Value::V6Set(_)) | (Value::V6Set(_), Value::V4Set(_)) => false,
-            _ => true,
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left,
+            right,
         }
     }
 
-    fn union(&self, ano: &Value) -> anyhow::Result<Value> {
-        if !self.is_same_len(&ano) {
-            return Err(anyhow!("Cannot add a v4 set to a v6 set")); // TODO: diagnostic
+    pub fn subset(&self, ano: &SetNode<MAX_DEPTH>) -> bool {
+        assert_eq!(ano.depth, self.depth);
+        if self.is_empty() || ano.covered {
+            return true;
         }
 
-        match (self, ano) {
-            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.union(r))),
-            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.union(r))),
-            _ => unreachable!(),
+        if self.covered || ano.is_empty() {
+            return false;
         }
+
+        let subset_option = |l: &Option<Rc<SetNode<MAX_DEPTH>>>, r: &Option<Rc<SetNode<MAX_DEPTH>>>| match (l, r) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(l), Some(r)) => l.subset(r),
+        };
+        subset_option(&self.left, &ano.left) && subset_option(&self.right, &ano.right)
     }
 
-    fn subtract(&self, ano: &Value) -> anyhow::Result<Value> {
-        if !self.is_same_len(&ano) {
-            return Err(anyhow!("Cannot subtract a v4 set to a v6 set")); // TODO: diagnostic
+    pub fn overlaps(&self, ano: &SetNode<MAX_DEPTH>) -> bool {
+        assert_eq!(ano.depth, self.depth);
+        if self.is_empty() || ano.is_empty() {
+            return false;
         }
 
-        match (self, ano) {
-            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.subtract(r))),
-            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.subtract(r))),
-            _ => unreachable!(),
+        if self.covered || ano.covered {
+            return true;
         }
-    }
-}
 
-fn construct_set_node<const MAX_DEPTH: usize>(addr: u128, len: usize, depth: usize) -> SetNode<MAX_DEPTH> {
-    if depth == len {
-        assert!(depth <= MAX_DEPTH);
-        return SetNode {
-            depth,
-            covered: true,
-            left: None,
-            right: None,
+        let overlaps_option = |l: &Option<Rc<SetNode<MAX_DEPTH>>>, r: &Option<Rc<SetNode<MAX_DEPTH>>>| match (l, r) {
+            (Some(l), Some(r)) => l.overlaps(r),
+            _ => false,
         };
+        overlaps_option(&self.left, &ano.left) || overlaps_option(&self.right, &ano.right)
     }
 
-    assert!(depth < MAX_DEPTH);
+    pub fn is_empty(&self) -> bool {
+        !self.covered && self.left.is_none() && self.right.is_none()
+    }
 
-    let child = Some(Rc::new(construct_set_node(addr, len, depth + 1)));
-    let mut cur = SetNode {
-        depth,
-        covered: false,
-        left: None,
-        right: None,
-    };
-    if (addr >> (MAX_DEPTH - depth - 1)) & 1 == 0 {
-        cur.left = child;
-    } else {
-        cur.right = child;
+    // Number of prefixes, i.e. covered nodes
+    pub fn count(&self) -> u128 {
+        if self.covered {
+            return 1;
+        }
+        [&self.left, &self.right].iter().filter_map(|c| c.as_ref()).map(|c| c.count()).sum()
     }
 
-    cur
-}
+    // Number of prefixes once those shorter than `len` are split up, as split() lists them
+    pub fn split_count(&self, len: usize) -> Number {
+        // Like size(), only the whole v6 space split into /128 overflows u128
+        if self.covered && MAX_DEPTH - self.depth == 128 && len == 128 {
+            return Number { negative: false, carry: true, low: 0 };
+        }
+        Number::from(self.split_count_below(len))
+    }
 
-impl From<&V4> for SetNode<32> {
-    fn from(value: &V4) -> Self {
-        construct_set_node(value.0 as u128, value.1 as usize, 0)
+    fn split_count_below(&self, len: usize) -> u128 {
+        if self.covered {
+            return 1 << len.saturating_sub(self.depth);
+        }
+        [&self.left, &self.right].iter().filter_map(|c| c.as_ref()).map(|c| c.split_count_below(len)).sum()
     }
-}
 
-impl From<(u128, usize)> for V4 {
-    fn from(value: (u128, usize)) -> Self {
-        Self(value.0 as u32, value.1 as u8)
+    // Number of addresses
+    pub fn size(&self) -> Number {
+        // Only the whole v6 space overflows u128. Anything else misses at least one address, because both children covered collapse
+        if self.covered && MAX_DEPTH - self.depth == 128 {
+            return Number { negative: false, carry: true, low: 0 };
+        }
+        Number::from(self.size_below())
     }
-}
 
-impl From<&V6> for SetNode<128> {
-    fn from(value: &V6) -> Self {
+    // Keeps the prefixes with length in min..=max
+    pub fn filter_len(&self, min: usize, max: usize) -> SetNode<MAX_DEPTH> {
+        if self.covered && (min..=max).contains(&self.depth) {
+            return self.clone();
+        }
+
+        // Everything below is too long
+        if self.covered || self.depth >= max {
+            return SetNode {
+                depth: self.depth,
+                covered: false,
+                left: None,
+                right: None,
+            };
+        }
+
+        // Only removes prefixes, so no siblings become both covered
+        let filter_option = |c: &Option<Rc<SetNode<MAX_DEPTH>>>| {
+            c.as_ref().map(|c| c.filter_len(min, max)).filter(|c| !c.is_empty()).map(Rc::new)
+        };
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left: filter_option(&self.left),
+            right: filter_option(&self.right),
+        }
+    }
+
+    // Rounds prefixes longer than `len` up to their covering prefix of that length
+    pub fn coarsen(&self, len: usize) -> SetNode<MAX_DEPTH> {
+        if self.covered || self.is_empty() {
+            return self.clone();
+        }
+
+        if self.depth >= len {
+            return SetNode {
+                depth: self.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
+        }
+
+        let left = self.left.as_ref().map(|c| Rc::new(c.coarsen(len)));
+        let right = self.right.as_ref().map(|c| Rc::new(c.coarsen(len)));
+        let covered = left.as_ref().is_some_and(|i| i.covered)
+            && right.as_ref().is_some_and(|i| i.covered);
+
+        if covered {
+            return SetNode {
+                depth: self.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
+        }
+
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left,
+            right,
+        }
+    }
+
+    // Drops the blocks of length `len` which are only partially covered, the dual of coarsen
+    pub fn interior(&self, len: usize) -> SetNode<MAX_DEPTH> {
+        if self.covered {
+            return self.clone();
+        }
+
+        if self.depth >= len {
+            return SetNode {
+                depth: self.depth,
+                covered: false,
+                left: None,
+                right: None,
+            };
+        }
+
+        // Only removes prefixes, so no siblings become both covered
+        let interior_option = |c: &Option<Rc<SetNode<MAX_DEPTH>>>| {
+            c.as_ref().map(|c| c.interior(len)).filter(|c| !c.is_empty()).map(Rc::new)
+        };
+        SetNode {
+            depth: self.depth,
+            covered: false,
+            left: interior_option(&self.left),
+            right: interior_option(&self.right),
+        }
+    }
+
+    // The prefix covering `addr` / `len`, if any. Canonical trees have at most one
+    pub fn lookup(&self, addr: u128, len: usize) -> Option<(u128, usize)> {
+        let mut cur = self;
+        while !cur.covered {
+            if cur.depth >= len {
+                return None;
+            }
+            let next = if goes_right::<MAX_DEPTH>(addr, cur.depth) { &cur.right } else { &cur.left };
+            cur = next.as_ref()?;
+        }
+        Some((addr & !host_mask(MAX_DEPTH - cur.depth), cur.depth))
+    }
+
+    // The only prefix in this set, if it is exactly one
+    pub fn single(&self) -> Option<(u128, usize)> {
+        let mut cur = self;
+        let mut addr = 0u128;
+        while !cur.covered {
+            let (next, bit) = match (&cur.left, &cur.right) {
+                (Some(l), None) => (l, 0),
+                (None, Some(r)) => (r, 1),
+                _ => return None,
+            };
+            addr |= bit << (MAX_DEPTH - cur.depth - 1);
+            cur = next;
+        }
+        Some((addr, cur.depth))
+    }
+
+    // The address at index `n`, in ascending order across prefixes
+    pub fn nth(&self, mut n: u128) -> Option<u128> {
+        let mut cur = self;
+        let mut addr = 0u128;
+        while !cur.covered {
+            let left_size = cur.left.as_ref().map_or(0, |l| l.size_below());
+            if n < left_size {
+                cur = cur.left.as_ref().unwrap();
+            } else {
+                n -= left_size;
+                addr |= 1 << (MAX_DEPTH - cur.depth - 1);
+                cur = cur.right.as_ref()?;
+            }
+        }
+        (n <= host_mask(MAX_DEPTH - cur.depth)).then_some(addr | n)
+    }
+
+    pub fn last(&self) -> Option<u128> {
+        let mut cur = self;
+        let mut addr = 0u128;
+        while !cur.covered {
+            cur = match (&cur.left, &cur.right) {
+                (_, Some(r)) => {
+                    addr |= 1 << (MAX_DEPTH - cur.depth - 1);
+                    r
+                }
+                (Some(l), None) => l,
+                (None, None) => return None,
+            };
+        }
+        Some(addr | host_mask(MAX_DEPTH - cur.depth))
+    }
+
+    // Smallest superset with at most `max` prefixes, and how many extra addresses it covers.
+    // None if there is no such superset, i.e. `max` is 0 for a non-empty set
+    pub fn summarize(&self, max: usize) -> Option<(SetNode<MAX_DEPTH>, u128)> {
+        // Skips the quadratic plan for the common case of a large list that already fits
+        if self.count() <= max as u128 {
+            return Some((self.clone(), 0));
+        }
+        let plan = self.plan_summary(max);
+        let extra = plan.cost(max)?;
+        Some((plan.build(max), extra))
+    }
+
+    fn plan_summary(&self, budget: usize) -> SummaryPlan<'_, MAX_DEPTH> {
+        if self.covered {
+            return SummaryPlan { node: self, costs: vec![None, Some(0)], cover: Some(0), left: None, right: None };
+        }
+
+        let left = self.left.as_ref().map(|c| Box::new(c.plan_summary(budget)));
+        let right = self.right.as_ref().map(|c| Box::new(c.plan_summary(budget)));
+        let most = |c: &Option<Box<SummaryPlan<MAX_DEPTH>>>| c.as_ref().map_or(0, |c| c.costs.len() - 1);
+        let (left_most, right_most) = (most(&left), most(&right));
+
+        // Spread the prefixes between both children
+        let mut costs = vec![None; (left_most + right_most).min(budget) + 1];
+        for kl in 0..=left_most.min(budget) {
+            for kr in 0..=right_most.min(budget - kl) {
+                let cost = child_cost(&left, kl).zip(child_cost(&right, kr)).map(|(l, r)| l + r);
+                costs[kl + kr] = cheaper(costs[kl + kr], cost);
+            }
+        }
+
+        // Or cover the whole node with one prefix. Non-empty here, so no overflow
+        let cover = (costs.len() > 1).then(|| host_mask(MAX_DEPTH - self.depth) - self.size_below() + 1);
+        for k in 1..costs.len() {
+            let cover = if k == 1 { cover } else { costs[k - 1] };
+            costs[k] = cheaper(costs[k], cover);
+        }
+
+        SummaryPlan { node: self, costs, cover, left, right }
+    }
+
+    fn size_below(&self) -> u128 {
+        if self.covered {
+            return 1 << (MAX_DEPTH - self.depth);
+        }
+        [&self.left, &self.right].iter().filter_map(|c| c.as_ref()).map(|c| c.size_below()).sum()
+    }
+
+    #[cfg(test)]
+    pub fn is_canonical(&self) -> bool {
+        if self.covered {
+            return self.left.is_none() && self.right.is_none();
+        }
+
+        let children_ok = [&self.left, &self.right].iter().all(|c| match c {
+            None => true,
+            Some(c) => c.depth == self.depth + 1 && !c.is_empty() && c.is_canonical(),
+        });
+        let both_covered = self.left.as_ref().is_some_and(|i| i.covered)
+            && self.right.as_ref().is_some_and(|i| i.covered);
+        children_ok && !both_covered
+    }
+}
+
+#[derive(Clone)]
+struct SetWalkerFrame<'a, const MAX_DEPTH: usize> {
+    node: &'a SetNode<MAX_DEPTH>,
+    inspected_branches: usize, // Inspected branches. Now always 0 / 1 / 2
+}
+
+// Iterator, stack top always pointing at a covered node, except in the terminal state, where the stack is empty
+struct SetWalker<'a, const MAX_DEPTH: usize> {
+    stack: VecDeque<SetWalkerFrame<'a, MAX_DEPTH>>
+}
+
+impl<'a, const MAX_DEPTH: usize> SetWalker<'a, MAX_DEPTH> {
+    pub fn new(n: &'a SetNode<MAX_DEPTH>) -> Self {
+        let mut s = Self {
+            stack: VecDeque::with_capacity(MAX_DEPTH + 1),
+        };
+        s.stack.push_back(SetWalkerFrame { node: n, inspected_branches: 0 });
+        s
+    }
+
+    fn step(&mut self) {
+        if self.stack.is_empty() {
+            assert!(self.stack.is_empty());
+            return;
+        }
+
+        let top_node = self.stack.back_mut().unwrap().node;
+        let top_inspected = &mut self.stack.back_mut().unwrap().inspected_branches;
+        match *top_inspected {
+            0 => {
+                *top_inspected += 1;
+                if let Some(ref cur) = top_node.left {
+                    self.stack.push_back(SetWalkerFrame { node: cur.as_ref(), inspected_branches: 0 })
+                }
+            },
+            1 => {
+                *top_inspected += 1;
+                if let Some(ref cur) = top_node.right {
+                    self.stack.push_back(SetWalkerFrame { node: cur.as_ref(), inspected_branches: 0 })
+                }
+            },
+            2 => {
+                self.stack.pop_back();
+            },
+            _ => unreachable!()
+        }
+    }
+}
+
+impl<'a, const MAX_DEPTH: usize> Iterator for SetWalker<'a, MAX_DEPTH> {
+    type Item = (u128, usize); // Address and prefix length
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while match self.stack.back() {
+            None => false,
+            Some(inner) => !inner.node.covered || inner.inspected_branches > 0
+        } {
+            self.step();
+        }
+
+        if self.stack.is_empty() {
+            return None;
+        }
+
+        // Arrived at a covered node
+        assert_eq!(self.stack.back().map(|e| e.node.covered), Some(true));
+
+        // Serialize stack
+        let mut addr = 0u128;
+        let mut len = 0;
+        for elem in &self.stack {
+            if elem.inspected_branches == 0 {
+                // Last one
+                break;
+            }
+            addr <<= 1;
+            addr |= (elem.inspected_branches - 1) as u128;
+            len += 1;
+        }
+
+        if len != 0 { // Avoid UB
+            addr <<= MAX_DEPTH - len;
+        }
+
+        self.step();
+
+        Some((addr, len))
+    }
+}
+
+// Host bits of a prefix with `bits` of them, avoiding overflow at 128
+fn host_mask(bits: usize) -> u128 {
+    u128::MAX.checked_shr(128 - bits as u32).unwrap_or(0)
+}
+
+// Cheapest covers of a subtree, for every number of prefixes up to a budget
+struct SummaryPlan<'a, const MAX_DEPTH: usize> {
+    node: &'a SetNode<MAX_DEPTH>,
+    // Fewest extra addresses with at most k prefixes at index k, None if impossible. Constant past the end
+    costs: Vec<Option<u128>>,
+    // Extra addresses when covering the whole node
+    cover: Option<u128>,
+    left: Option<Box<SummaryPlan<'a, MAX_DEPTH>>>,
+    right: Option<Box<SummaryPlan<'a, MAX_DEPTH>>>,
+}
+
+fn cheaper(a: Option<u128>, b: Option<u128>) -> Option<u128> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Missing children need no prefixes
+fn child_cost<const MAX_DEPTH: usize>(c: &Option<Box<SummaryPlan<MAX_DEPTH>>>, k: usize) -> Option<u128> {
+    c.as_ref().map_or(Some(0), |c| c.cost(k))
+}
+
+impl<const MAX_DEPTH: usize> SummaryPlan<'_, MAX_DEPTH> {
+    fn cost(&self, k: usize) -> Option<u128> {
+        self.costs[k.min(self.costs.len() - 1)]
+    }
+
+    // Follows the choices behind cost(k)
+    fn build(&self, k: usize) -> SetNode<MAX_DEPTH> {
+        let target = self.cost(k);
+        if self.node.covered {
+            return self.node.clone();
+        }
+
+        // Prefer one prefix on ties
+        if k >= 1 && self.cover == target {
+            return SetNode {
+                depth: self.node.depth,
+                covered: true,
+                left: None,
+                right: None,
+            };
+        }
+
+        // Costs never increase with k, so some split uses exactly k
+        let kl = (0..=k)
+            .find(|kl| child_cost(&self.left, *kl).zip(child_cost(&self.right, k - kl)).map(|(l, r)| l + r) == target)
+            .unwrap();
+        let left = self.left.as_ref().map(|c| Rc::new(c.build(kl)));
+        let right = self.right.as_ref().map(|c| Rc::new(c.build(k - kl)));
+        SetNode {
+            depth: self.node.depth,
+            covered: false,
+            left,
+            right,
+        }
+    }
+}
+
+// Like SetWalker, but prefixes shorter than `len` are expanded into subnets of that length, one at a time
+struct SplitWalker<'a, const MAX_DEPTH: usize> {
+    inner: SetWalker<'a, MAX_DEPTH>,
+    len: usize,
+    // Next and last subnet of the prefix being expanded
+    pending: Option<(u128, u128)>,
+}
+
+impl<'a, const MAX_DEPTH: usize> SplitWalker<'a, MAX_DEPTH> {
+    pub fn new(n: &'a SetNode<MAX_DEPTH>, len: usize) -> Self {
+        Self { inner: SetWalker::new(n), len, pending: None }
+    }
+}
+
+impl<'a, const MAX_DEPTH: usize> Iterator for SplitWalker<'a, MAX_DEPTH> {
+    type Item = (u128, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((next, last)) = self.pending {
+            self.pending = (next != last).then(|| (next + (host_mask(MAX_DEPTH - self.len) + 1), last));
+            return Some((next, self.len));
+        }
+
+        let (addr, len) = self.inner.next()?;
+        if len >= self.len {
+            return Some((addr, len));
+        }
+        let last = addr | (host_mask(MAX_DEPTH - len) & !host_mask(MAX_DEPTH - self.len));
+        self.pending = Some((addr, last));
+        self.next()
+    }
+}
+
+#[derive(Debug)]
+pub struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Expr<'static>,
+    // Captured at definition
+    scope: Scope,
+}
+
+// Guards against runaway recursion, which would otherwise overflow the stack
+const MAX_CALL_DEPTH: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Unit,
+    V4Set(SetNode<32>),
+    V6Set(SetNode<128>),
+    Bool(bool),
+    Number(Number),
+    // Set with prefixes shorter than the length split up, only expanded when formatted
+    V4Split(SetNode<32>, usize),
+    V6Split(SetNode<128>, usize),
+    // Result of summarize(), with the number of addresses it added
+    V4Summary(SetNode<32>, u128),
+    V6Summary(SetNode<128>, u128),
+    // Binding names with their prefix covering the looked up address
+    Lookup(Vec<(String, String)>),
+    Function(Rc<Function>),
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Unit => "unit",
+            Value::V4Set(_) => "v4 set",
+            Value::V6Set(_) => "v6 set",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::V4Split(..) => "v4 subnet list",
+            Value::V6Split(..) => "v6 subnet list",
+            Value::V4Summary(..) => "v4 set",
+            Value::V6Summary(..) => "v6 set",
+            Value::Lookup(_) => "lookup result",
+            Value::Function(_) => "function",
+        }
+    }
+
+    // Subnet lists and summaries stand for the same addresses wherever a set is expected
+    fn as_set(&self) -> Cow<'_, Value> {
+        match self {
+            Value::V4Split(s, _) | Value::V4Summary(s, _) => Cow::Owned(Value::V4Set(s.clone())),
+            Value::V6Split(s, _) | Value::V6Summary(s, _) => Cow::Owned(Value::V6Set(s.clone())),
+            v => Cow::Borrowed(v),
+        }
+    }
+
+    fn is_same_len(&self, ano: &Value) -> bool {
+        !matches!(
+            (self.as_set().as_ref(), ano.as_set().as_ref()),
+            (Value::V4Set(_), Value::V6Set(_)) | (Value::V6Set(_), Value::V4Set(_))
+        )
+    }
+
+    fn union(&self, ano: &Value) -> anyhow::Result<Value> {
+        if !self.is_same_len(ano) {
+            return Err(anyhow!("Cannot add a v4 set to a v6 set")); // TODO: diagnostic
+        }
+
+        match (self.as_set().as_ref(), ano.as_set().as_ref()) {
+            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.union(r))),
+            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.union(r))),
+            (l, r) => Err(anyhow!("Cannot add a {} to a {}", r.type_name(), l.type_name())),
+        }
+    }
+
+    fn subtract(&self, ano: &Value) -> anyhow::Result<Value> {
+        if !self.is_same_len(ano) {
+            return Err(anyhow!("Cannot subtract a v4 set to a v6 set")); // TODO: diagnostic
+        }
+
+        match (self.as_set().as_ref(), ano.as_set().as_ref()) {
+            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.subtract(r))),
+            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.subtract(r))),
+            (l, r) => Err(anyhow!("Cannot subtract a {} from a {}", r.type_name(), l.type_name())),
+        }
+    }
+
+    fn intersect(&self, ano: &Value) -> anyhow::Result<Value> {
+        if !self.is_same_len(ano) {
+            return Err(anyhow!("Cannot intersect a v4 set with a v6 set")); // TODO: diagnostic
+        }
+
+        match (self.as_set().as_ref(), ano.as_set().as_ref()) {
+            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.intersect(r))),
+            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.intersect(r))),
+            (l, r) => Err(anyhow!("Cannot intersect a {} with a {}", l.type_name(), r.type_name())),
+        }
+    }
+
+    fn symmetric_difference(&self, ano: &Value) -> anyhow::Result<Value> {
+        if !self.is_same_len(ano) {
+            return Err(anyhow!("Cannot take the symmetric difference of a v4 set and a v6 set")); // TODO: diagnostic
+        }
+
+        match (self.as_set().as_ref(), ano.as_set().as_ref()) {
+            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::V4Set(l.symmetric_difference(r))),
+            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::V6Set(l.symmetric_difference(r))),
+            (l, r) => Err(anyhow!("Cannot take the symmetric difference of a {} and a {}", l.type_name(), r.type_name())),
+        }
+    }
+
+    fn predicate(&self, op: Predicate, ano: &Value) -> anyhow::Result<Value> {
+        fn apply<const MAX_DEPTH: usize>(op: Predicate, l: &SetNode<MAX_DEPTH>, r: &SetNode<MAX_DEPTH>) -> bool {
+            match op {
+                Predicate::Contains => r.subset(l),
+                Predicate::Subset => l.subset(r),
+                Predicate::Overlaps => l.overlaps(r),
+                Predicate::Equal => l == r,
+                Predicate::NotEqual => l != r,
+            }
+        }
+
+        if !self.is_same_len(ano) {
+            return Err(anyhow!("Cannot compare a v4 set with a v6 set")); // TODO: diagnostic
+        }
+
+        match (self.as_set().as_ref(), ano.as_set().as_ref()) {
+            (Value::V4Set(l), Value::V4Set(r)) => Ok(Value::Bool(apply(op, l, r))),
+            (Value::V6Set(l), Value::V6Set(r)) => Ok(Value::Bool(apply(op, l, r))),
+            (l, r) => Err(anyhow!("Cannot compare a {} with a {}", l.type_name(), r.type_name())),
+        }
+    }
+
+    fn complement(&self) -> anyhow::Result<Value> {
+        match self.as_set().as_ref() {
+            Value::V4Set(s) => Ok(Value::V4Set(s.complement())),
+            Value::V6Set(s) => Ok(Value::V6Set(s.complement())),
+            v => Err(anyhow!("Cannot complement a {}", v.type_name())),
+        }
+    }
+}
+
+// Which child of a node at `depth` the address falls under
+fn goes_right<const MAX_DEPTH: usize>(addr: u128, depth: usize) -> bool {
+    (addr >> (MAX_DEPTH - depth - 1)) & 1 == 1
+}
+
+fn construct_set_node<const MAX_DEPTH: usize>(addr: u128, len: usize, depth: usize) -> SetNode<MAX_DEPTH> {
+    if depth == len {
+        assert!(depth <= MAX_DEPTH);
+        return SetNode {
+            depth,
+            covered: true,
+            left: None,
+            right: None,
+        };
+    }
+
+    assert!(depth < MAX_DEPTH);
+
+    let child = Some(Rc::new(construct_set_node(addr, len, depth + 1)));
+    let mut cur = SetNode {
+        depth,
+        covered: false,
+        left: None,
+        right: None,
+    };
+    if goes_right::<MAX_DEPTH>(addr, depth) {
+        cur.right = child;
+    } else {
+        cur.left = child;
+    }
+
+    cur
+}
+
+// Builds the minimal cover of the inclusive ranges under the node at `base` / `depth`.
+// Ranges must be sorted, disjoint and non-adjacent (see `construct_ranges_set`), and all intersecting this node
+fn construct_ranges_node<const MAX_DEPTH: usize>(ranges: &[(u128, u128)], base: u128, depth: usize) -> SetNode<MAX_DEPTH> {
+    let span = MAX_DEPTH - depth;
+    let last = if span == 128 {
+        // Avoid overflow
+        u128::MAX
+    } else {
+        base | ((1u128 << span) - 1)
+    };
+
+    let mut cur = SetNode {
+        depth,
+        covered: false,
+        left: None,
+        right: None,
+    };
+
+    if ranges.is_empty() {
+        return cur;
+    }
+
+    // Merged ranges never share a node they fully cover
+    if ranges[0].0 <= base && last <= ranges[0].1 {
+        cur.covered = true;
+        return cur;
+    }
+
+    assert!(depth < MAX_DEPTH);
+
+    let mid = base | (1u128 << (span - 1));
+    let left_cnt = ranges.partition_point(|r| r.0 < mid);
+    let right_start = ranges.partition_point(|r| r.1 < mid);
+    let left = construct_ranges_node(&ranges[..left_cnt], base, depth + 1);
+    let right = construct_ranges_node(&ranges[right_start..], mid, depth + 1);
+    if !left.is_empty() {
+        cur.left = Some(Rc::new(left));
+    }
+    if !right.is_empty() {
+        cur.right = Some(Rc::new(right));
+    }
+
+    cur
+}
+
+// Bulk construction from arbitrary inclusive ranges
+fn construct_ranges_set<const MAX_DEPTH: usize>(mut ranges: Vec<(u128, u128)>) -> SetNode<MAX_DEPTH> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            // start > 0 here, so no underflow
+            Some(last) if start <= last.1 || start - 1 == last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    construct_ranges_node(&merged, 0, 0)
+}
+
+impl From<&V4> for SetNode<32> {
+    fn from(value: &V4) -> Self {
+        construct_set_node(value.0 as u128, value.1 as usize, 0)
+    }
+}
+
+impl From<(u128, usize)> for V4 {
+    fn from(value: (u128, usize)) -> Self {
+        Self(value.0 as u32, value.1 as u8)
+    }
+}
+
+impl From<&V6> for SetNode<128> {
+    fn from(value: &V6) -> Self {
         construct_set_node(value.0, value.1 as usize, 0)
     }
 }
 
-impl From<(u128, usize)> for V6 {
-    fn from(value: (u128, usize)) -> Self {
-        Self(value.0, value.1 as u8)
+impl From<(u128, usize)> for V6 {
+    fn from(value: (u128, usize)) -> Self {
+        Self(value.0, value.1 as u8)
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct Scope {
+    bindings: im::HashMap<String, Value>,
+    // Built-in bindings, shadowed by `bindings`. Kept apart so imports only export user bindings
+    prelude: im::HashMap<String, Value>,
+    // Nesting level of function calls
+    depth: usize,
+    // Files being evaluated, innermost last. Relative imports are resolved against the innermost one
+    files: im::Vector<PathBuf>,
+    // For imported and loaded files
+    options: ParseOptions,
+    warnings: Rc<RefCell<Vec<Warning>>>,
+}
+
+const PRELUDE: &str = include_str!("prelude.cidr");
+
+impl Scope {
+    // Scope with the IANA special-purpose address sets bound
+    pub fn prelude() -> Scope {
+        let parsed = parse(PRELUDE).expect("Prelude should parse");
+        let (_, evaled) = eval_all(&parsed, Scope::default()).expect("Prelude should evaluate");
+        Scope {
+            prelude: evaled.bindings,
+            ..Default::default()
+        }
+    }
+
+    pub fn keys<'s>(&'s self) -> impl Iterator<Item = &'s str> + 's {
+        let shadowed = |k: &&String| !self.bindings.contains_key(*k);
+        self.bindings.keys().chain(self.prelude.keys().filter(shadowed)).map(String::as_str)
+    }
+
+    fn get(&self, ident: &str) -> Option<&Value> {
+        self.bindings.get(ident).or_else(|| self.prelude.get(ident))
+    }
+
+    pub fn with_options(mut self, options: ParseOptions) -> Scope {
+        self.options = options;
+        self
+    }
+
+    // Warnings from imported and loaded files so far, shared by all scopes derived from this one
+    pub fn take_warnings(&self) -> Vec<Warning> {
+        self.warnings.take()
+    }
+
+    // Marks this scope as evaluating the given file
+    pub fn in_file(mut self, path: &Path) -> anyhow::Result<Scope> {
+        let path = path.canonicalize().map_err(|e| anyhow!("Cannot open {}: {}", path.display(), e))?;
+        self.files.push_back(path);
+        Ok(self)
+    }
+}
+
+pub fn eval<'a>(stmts: &Vec<Stmt<'a>>) -> anyhow::Result<Vec<Value>> {
+    eval_in(stmts, Scope::prelude())
+}
+
+pub fn eval_in<'a>(stmts: &Vec<Stmt<'a>>, scope: Scope) -> anyhow::Result<Vec<Value>> {
+    eval_all(stmts, scope).map(|(output, _)| output)
+}
+
+fn eval_all<'a>(stmts: &Vec<Stmt<'a>>, mut scope: Scope) -> anyhow::Result<(Vec<Value>, Scope)> {
+    let mut output = Vec::with_capacity(stmts.len());
+
+    for stmt in stmts {
+        let (v, s) = eval_stmt(stmt, scope)?;
+        scope = s;
+        output.push(v);
+    }
+
+    Ok((output, scope))
+}
+
+// Relative to the file being evaluated, or the working directory in the REPL
+fn resolve_path(path: &str, s: &Scope) -> PathBuf {
+    match s.files.last().and_then(|f| f.parent()) {
+        Some(base) => base.join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+// Without a family, mixing v4 and v6 entries is an error. An empty list is an empty v4 set
+fn load(path: &str, family: Option<Family>, s: &Scope) -> anyhow::Result<Value> {
+    let target = resolve_path(path, s);
+    let content = std::fs::read_to_string(&target).map_err(|e| anyhow!("Cannot load {}: {}", target.display(), e))?;
+
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    let mut first_line = [None, None];
+    for (idx, line) in content.lines().enumerate() {
+        let mut warnings = Vec::new();
+        let entry = parse_list_entry(line, s.options, &mut warnings)
+            .map_err(|e| anyhow!("{}:{}: Invalid entry `{}`\n{}", target.display(), idx + 1, line.trim(), e))?;
+        s.warnings.borrow_mut().extend(warnings.into_iter().map(|w| Warning {
+            file: Some(target.clone()),
+            pos: w.pos.map(|(_, col)| (idx + 1, col)),
+            ..w
+        }));
+        let Some((entry_family, start, end)) = entry else { continue };
+        match entry_family {
+            Family::V4 => v4.push((start, end)),
+            Family::V6 => v6.push((start, end)),
+        }
+        first_line[entry_family as usize].get_or_insert(idx + 1);
+    }
+
+    let family = match family {
+        Some(f) => f,
+        None if v4.is_empty() && !v6.is_empty() => Family::V6,
+        None if v6.is_empty() => Family::V4,
+        None => return Err(anyhow!(
+            "{} mixes v4 (first at line {}) and v6 (first at line {}) entries, use load(\"{}\", v4) or load(\"{}\", v6) to pick one",
+            target.display(), first_line[0].unwrap(), first_line[1].unwrap(), path, path,
+        )),
+    };
+
+    Ok(match family {
+        Family::V4 => Value::V4Set(construct_ranges_set(v4)),
+        Family::V6 => Value::V6Set(construct_ranges_set(v6)),
+    })
+}
+
+fn import(path: &str, s: &Scope) -> anyhow::Result<Scope> {
+    let target = resolve_path(path, s);
+    let target = target.canonicalize().map_err(|e| anyhow!("Cannot import {}: {}", target.display(), e))?;
+    if s.files.contains(&target) {
+        let chain = s.files.iter().chain(iter::once(&target)).map(|f| f.display().to_string()).collect::<Vec<_>>();
+        return Err(anyhow!("Import cycle: {}", chain.join(" -> ")));
+    }
+
+    let content = std::fs::read_to_string(&target).map_err(|e| anyhow!("Cannot import {}: {}", target.display(), e))?;
+    let mut warnings = Vec::new();
+    let parsed = parse_with(&content, s.options, &mut warnings).map_err(|e| anyhow!("In {}:\n{}", target.display(), e))?;
+    s.warnings.borrow_mut().extend(warnings.into_iter().map(|w| Warning {
+        file: Some(target.clone()),
+        ..w
+    }));
+    let mut module = Scope {
+        prelude: s.prelude.clone(),
+        files: s.files.clone(),
+        options: s.options,
+        warnings: s.warnings.clone(),
+        ..Default::default()
+    };
+    module.files.push_back(target.clone());
+    let (_, module) = eval_all(&parsed, module).map_err(|e| anyhow!("In {}:\n{}", target.display(), e))?;
+    Ok(module)
+}
+
+struct Builtin {
+    name: &'static str,
+    params: &'static [&'static str],
+    call: fn(&[Value], &Scope) -> anyhow::Result<Value>,
+}
+
+// Looked up after the scope, so user bindings can shadow them
+const BUILTINS: &[Builtin] = &[
+    Builtin { name: "count", params: &["x"], call: builtin_count },
+    Builtin { name: "size", params: &["x"], call: builtin_size },
+    Builtin { name: "filter_len", params: &["x", "min", "max"], call: builtin_filter_len },
+    Builtin { name: "split", params: &["x", "len"], call: builtin_split },
+    Builtin { name: "summarize", params: &["x", "max_prefixes"], call: builtin_summarize },
+    Builtin { name: "coarsen", params: &["x", "len"], call: builtin_coarsen },
+    Builtin { name: "interior", params: &["x", "len"], call: builtin_interior },
+    Builtin { name: "lookup", params: &["addr"], call: builtin_lookup },
+    Builtin { name: "first", params: &["x"], call: builtin_first },
+    Builtin { name: "last", params: &["x"], call: builtin_last },
+    Builtin { name: "nth", params: &["x", "n"], call: builtin_nth },
+    Builtin { name: "hosts", params: &["x"], call: builtin_hosts },
+    Builtin { name: "next", params: &["p"], call: builtin_next },
+    Builtin { name: "prev", params: &["p"], call: builtin_prev },
+    Builtin { name: "offset", params: &["p", "k"], call: builtin_offset },
+    Builtin { name: "parent", params: &["p", "n"], call: builtin_parent },
+    Builtin { name: "sibling", params: &["p"], call: builtin_sibling },
+    Builtin { name: "children", params: &["p", "len"], call: builtin_children },
+];
+
+// Prefix length argument, within the family of the set it applies to
+fn len_arg(v: &Value, max_depth: usize) -> anyhow::Result<usize> {
+    match v {
+        Value::Number(n) => match n.to_u128() {
+            Some(len) if len <= max_depth as u128 => Ok(len as usize),
+            _ => Err(anyhow!("Prefix length {} is out of range 0-{}", n, max_depth)),
+        },
+        v => Err(anyhow!("Expected a prefix length, but got a {}", v.type_name())),
     }
 }
 
-#[derive(Clone)]
-pub struct Scope {
-    bindings: im::HashMap<String, Value>,
+fn builtin_count(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match (&args[0], args[0].as_set().as_ref()) {
+        // Each subnet as listed
+        (Value::V4Split(s, len), _) => Ok(Value::Number(s.split_count(*len))),
+        (Value::V6Split(s, len), _) => Ok(Value::Number(s.split_count(*len))),
+        (_, Value::V4Set(s)) => Ok(Value::Number(s.count().into())),
+        (_, Value::V6Set(s)) => Ok(Value::Number(s.count().into())),
+        (v, _) => Err(anyhow!("Cannot count the prefixes of a {}", v.type_name())),
+    }
 }
 
-impl Default for Scope {
-    fn default() -> Self {
-        Self { bindings: Default::default() }
+fn builtin_size(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::Number(s.size())),
+        Value::V6Set(s) => Ok(Value::Number(s.size())),
+        v => Err(anyhow!("Cannot take the size of a {}", v.type_name())),
     }
 }
 
-impl Scope {
-    pub fn keys<'s>(&'s self) -> impl Iterator<Item = &'s str> + 's {
-        self.bindings.keys().map(String::as_str)
+fn builtin_filter_len(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    fn apply<const MAX_DEPTH: usize>(s: &SetNode<MAX_DEPTH>, args: &[Value]) -> anyhow::Result<SetNode<MAX_DEPTH>> {
+        let min = len_arg(&args[1], MAX_DEPTH)?;
+        let max = len_arg(&args[2], MAX_DEPTH)?;
+        if min > max {
+            return Err(anyhow!("Minimum length {} is greater than maximum length {}", min, max));
+        }
+        Ok(s.filter_len(min, max))
+    }
+
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(apply(s, args)?)),
+        Value::V6Set(s) => Ok(Value::V6Set(apply(s, args)?)),
+        v => Err(anyhow!("Cannot filter a {} by prefix length", v.type_name())),
     }
 }
 
-pub fn eval<'a>(stmts: &Vec<Stmt<'a>>) -> anyhow::Result<Vec<Value>> {
-    let mut scope = Scope {
-        bindings: Default::default()
+fn builtin_split(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Split(s.clone(), len_arg(&args[1], 32)?)),
+        Value::V6Set(s) => Ok(Value::V6Split(s.clone(), len_arg(&args[1], 128)?)),
+        v => Err(anyhow!("Cannot split a {}", v.type_name())),
+    }
+}
+
+fn builtin_coarsen(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(s.coarsen(len_arg(&args[1], 32)?))),
+        Value::V6Set(s) => Ok(Value::V6Set(s.coarsen(len_arg(&args[1], 128)?))),
+        v => Err(anyhow!("Cannot coarsen a {}", v.type_name())),
+    }
+}
+
+fn builtin_interior(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(s.interior(len_arg(&args[1], 32)?))),
+        Value::V6Set(s) => Ok(Value::V6Set(s.interior(len_arg(&args[1], 128)?))),
+        v => Err(anyhow!("Cannot take the interior of a {}", v.type_name())),
+    }
+}
+
+fn builtin_first(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => s.nth(0).map(|a| Value::V4Set(construct_set_node(a, 32, 0))),
+        Value::V6Set(s) => s.nth(0).map(|a| Value::V6Set(construct_set_node(a, 128, 0))),
+        v => return Err(anyhow!("Cannot take the first address of a {}", v.type_name())),
+    }.ok_or_else(|| anyhow!("Cannot take the first address of an empty set"))
+}
+
+fn builtin_last(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => s.last().map(|a| Value::V4Set(construct_set_node(a, 32, 0))),
+        Value::V6Set(s) => s.last().map(|a| Value::V6Set(construct_set_node(a, 128, 0))),
+        v => return Err(anyhow!("Cannot take the last address of a {}", v.type_name())),
+    }.ok_or_else(|| anyhow!("Cannot take the last address of an empty set"))
+}
+
+// Zero-based, so nth(x, 0) is first(x)
+fn builtin_nth(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    let n = match &args[1] {
+        Value::Number(n) => n.to_u128().ok_or_else(|| anyhow!("Index {} is out of range", n))?,
+        v => return Err(anyhow!("Expected an index, but got a {}", v.type_name())),
     };
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => s.nth(n).map(|a| Value::V4Set(construct_set_node(a, 32, 0))).ok_or_else(|| s.size()),
+        Value::V6Set(s) => s.nth(n).map(|a| Value::V6Set(construct_set_node(a, 128, 0))).ok_or_else(|| s.size()),
+        v => return Err(anyhow!("Cannot index into a {}", v.type_name())),
+    }.map_err(|size| anyhow!("Index {} is out of range for a set of {} addresses", n, size))
+}
 
-    let mut output = Vec::with_capacity(stmts.len());
+// Every address, as /32 or /128. Capped like split() when formatted
+fn builtin_hosts(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Split(s.clone(), 32)),
+        Value::V6Set(s) => Ok(Value::V6Split(s.clone(), 128)),
+        v => Err(anyhow!("Cannot enumerate the addresses of a {}", v.type_name())),
+    }
+}
 
-    for stmt in stmts {
-        let (v, s) = eval_stmt(stmt, scope)?;
-        scope = s;
-        output.push(v);
+fn prefix_string<const MAX_DEPTH: usize>(prefix: (u128, usize)) -> String {
+    if MAX_DEPTH == 32 {
+        V4::from(prefix).to_string()
+    } else {
+        V6::from(prefix).to_string()
+    }
+}
+
+// For functions working on a single prefix rather than a whole set
+fn single_prefix<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>) -> anyhow::Result<(u128, usize)> {
+    set.single().ok_or_else(|| anyhow!("Expected a single prefix, but got a set of {} prefixes", set.count()))
+}
+
+// The block `k` blocks of the same length away
+fn offset_prefix<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>, k: Number) -> anyhow::Result<SetNode<MAX_DEPTH>> {
+    let (addr, len) = single_prefix(set)?;
+    let span = (MAX_DEPTH - len) as u32;
+    let idx = addr.checked_shr(span).unwrap_or(0);
+    let moved = match (k.negative, k.carry) {
+        (_, true) => None,
+        (false, false) => idx.checked_add(k.low).filter(|i| *i <= host_mask(len)),
+        (true, false) => idx.checked_sub(k.low),
+    };
+    let moved = moved.ok_or_else(|| anyhow!(
+        "Cannot move {} by {} blocks, past the {} of the v{} space",
+        prefix_string::<MAX_DEPTH>((addr, len)), k, if k.negative { "start" } else { "end" }, if MAX_DEPTH == 32 { 4 } else { 6 },
+    ))?;
+    Ok(construct_set_node(moved.checked_shl(span).unwrap_or(0), len, 0))
+}
+
+fn offset_value(p: &Value, k: Number) -> anyhow::Result<Value> {
+    match p.as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(offset_prefix(s, k)?)),
+        Value::V6Set(s) => Ok(Value::V6Set(offset_prefix(s, k)?)),
+        v => Err(anyhow!("Cannot offset a {}", v.type_name())),
+    }
+}
+
+fn builtin_next(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    offset_value(&args[0], Number::from(1))
+}
+
+fn builtin_prev(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    offset_value(&args[0], Number { negative: true, carry: false, low: 1 })
+}
+
+fn builtin_offset(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    match &args[1] {
+        Value::Number(k) => offset_value(&args[0], *k),
+        v => Err(anyhow!("Expected a number of blocks, but got a {}", v.type_name())),
+    }
+}
+
+// The covering prefix `n` levels up
+fn builtin_parent(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    fn apply<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>, n: &Value) -> anyhow::Result<SetNode<MAX_DEPTH>> {
+        let (addr, len) = single_prefix(set)?;
+        let n = len_arg(n, MAX_DEPTH)?;
+        if n > len {
+            return Err(anyhow!("{} has no parent {} levels up", prefix_string::<MAX_DEPTH>((addr, len)), n));
+        }
+        Ok(construct_set_node(addr, len - n, 0))
+    }
+
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(apply(s, &args[1])?)),
+        Value::V6Set(s) => Ok(Value::V6Set(apply(s, &args[1])?)),
+        v => Err(anyhow!("Cannot take the parent of a {}", v.type_name())),
+    }
+}
+
+// The other half of the parent
+fn builtin_sibling(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    fn apply<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>) -> anyhow::Result<SetNode<MAX_DEPTH>> {
+        let (addr, len) = single_prefix(set)?;
+        if len == 0 {
+            return Err(anyhow!("{} has no sibling", prefix_string::<MAX_DEPTH>((addr, len))));
+        }
+        Ok(construct_set_node(addr ^ (1 << (MAX_DEPTH - len)), len, 0))
+    }
+
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Set(apply(s)?)),
+        Value::V6Set(s) => Ok(Value::V6Set(apply(s)?)),
+        v => Err(anyhow!("Cannot take the sibling of a {}", v.type_name())),
+    }
+}
+
+// As a subnet list, since the set of all children is just the prefix itself
+fn builtin_children(args: &[Value], _: &Scope) -> anyhow::Result<Value> {
+    fn apply<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>, len: &Value) -> anyhow::Result<usize> {
+        let prefix = single_prefix(set)?;
+        let len = len_arg(len, MAX_DEPTH)?;
+        if len < prefix.1 {
+            return Err(anyhow!("Cannot split {} into shorter /{} prefixes", prefix_string::<MAX_DEPTH>(prefix), len));
+        }
+        Ok(len)
+    }
+
+    match args[0].as_set().as_ref() {
+        Value::V4Set(s) => Ok(Value::V4Split(s.clone(), apply(s, &args[1])?)),
+        Value::V6Set(s) => Ok(Value::V6Split(s.clone(), apply(s, &args[1])?)),
+        v => Err(anyhow!("Cannot take the children of a {}", v.type_name())),
+    }
+}
+
+// Longest-prefix match against every set in scope, most specific first
+fn builtin_lookup(args: &[Value], s: &Scope) -> anyhow::Result<Value> {
+    let target = match args[0].as_set().as_ref() {
+        Value::V4Set(set) => set.single(),
+        Value::V6Set(set) => set.single(),
+        v => return Err(anyhow!("Cannot look up a {}", v.type_name())),
+    };
+    let (addr, len) = target.ok_or_else(|| anyhow!("Can only look up a single prefix"))?;
+
+    let mut matches = Vec::new();
+    for name in s.keys() {
+        let found = match (args[0].as_set().as_ref(), s.get(name).map(Value::as_set).as_deref()) {
+            (Value::V4Set(_), Some(Value::V4Set(set))) => set.lookup(addr, len).map(|p| (p.1, V4::from(p).to_string())),
+            (Value::V6Set(_), Some(Value::V6Set(set))) => set.lookup(addr, len).map(|p| (p.1, V6::from(p).to_string())),
+            _ => None,
+        };
+        if let Some((len, prefix)) = found {
+            matches.push((len, name.to_string(), prefix));
+        }
+    }
+
+    matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    Ok(Value::Lookup(matches.into_iter().map(|(_, name, prefix)| (name, prefix)).collect()))
+}
+
+// Keeps the extra addresses with the result, and also warns about them, as the result is lossy
+fn builtin_summarize(args: &[Value], s: &Scope) -> anyhow::Result<Value> {
+    fn apply<const MAX_DEPTH: usize>(set: &SetNode<MAX_DEPTH>, max: usize, s: &Scope) -> anyhow::Result<(SetNode<MAX_DEPTH>, u128)> {
+        let (summarized, extra) = set.summarize(max)
+            .ok_or_else(|| anyhow!("Cannot summarize a non-empty set into 0 prefixes"))?;
+        if extra > 0 {
+            s.warnings.borrow_mut().push(Warning {
+                file: s.files.last().cloned(),
+                pos: None,
+                message: format!("summarize() added {} extra addresses", extra),
+            });
+        }
+        Ok((summarized, extra))
+    }
+
+    let max = match &args[1] {
+        Value::Number(n) if n.negative => return Err(anyhow!("Cannot summarize into {} prefixes", n)),
+        // Anything beyond usize is as good as unlimited
+        Value::Number(n) => n.to_u128().map_or(usize::MAX, |n| n.try_into().unwrap_or(usize::MAX)),
+        v => return Err(anyhow!("Expected a number of prefixes, but got a {}", v.type_name())),
+    };
+    match args[0].as_set().as_ref() {
+        Value::V4Set(set) => apply(set, max, s).map(|(set, extra)| Value::V4Summary(set, extra)),
+        Value::V6Set(set) => apply(set, max, s).map(|(set, extra)| Value::V6Summary(set, extra)),
+        v => Err(anyhow!("Cannot summarize a {}", v.type_name())),
+    }
+}
+
+// Matches positional and named arguments to the parameters, in parameter order
+fn bind_args<'e, 'a>(
+    func: &str,
+    params: &[impl AsRef<str>],
+    args: &'e [Expr<'a>],
+    named: &'e [(Cow<'a, str>, Expr<'a>)],
+) -> anyhow::Result<Vec<&'e Expr<'a>>> {
+    if args.len() + named.len() != params.len() {
+        return Err(anyhow!("Function {} takes {} argument(s), but {} given", func, params.len(), args.len() + named.len()));
+    }
+
+    let mut bound: Vec<Option<&Expr>> = args.iter().map(Some).collect();
+    bound.resize(params.len(), None);
+    for (name, arg) in named {
+        let idx = params.iter().position(|p| p.as_ref() == name.as_ref())
+            .ok_or_else(|| anyhow!("Function {} has no parameter named {}", func, name))?;
+        if bound[idx].replace(arg).is_some() {
+            return Err(anyhow!("Argument {} given twice when calling {}", name, func));
+        }
+    }
+    // Counts match and nothing is given twice, so all are bound
+    Ok(bound.into_iter().map(Option::unwrap).collect())
+}
+
+// Keeps e.g. split(::/0, 64) from hanging the REPL or the web page
+const SPLIT_LIMIT: usize = 65536;
+
+fn capped<'a>(lines: impl Iterator<Item = String> + 'a) -> Box<dyn Iterator<Item = String> + 'a> {
+    Box::new(lines.take(SPLIT_LIMIT))
+}
+
+// Every subnet of a split value, lazily expanded and without the cap of format()
+pub fn subnets(v: &Value) -> Option<Box<dyn Iterator<Item = String> + '_>> {
+    match v {
+        Value::V4Split(s, len) => Some(Box::new(SplitWalker::new(s, *len).map(V4::from).map(|e| e.to_string()))),
+        Value::V6Split(s, len) => Some(Box::new(SplitWalker::new(s, *len).map(V6::from).map(|e| e.to_string()))),
+        _ => None,
     }
+}
 
-    Ok(output)
+// Kept out of the formatted lines, so everything printed is a subnet
+pub fn truncation_warning(v: &Value) -> Option<Warning> {
+    let total = match v {
+        Value::V4Split(s, len) => s.split_count(*len),
+        Value::V6Split(s, len) => s.split_count(*len),
+        _ => return None,
+    };
+    total.to_u128().is_none_or(|n| n > SPLIT_LIMIT as u128).then(|| Warning {
+        file: None,
+        pos: None,
+        message: format!("Showing only the first {} of {} subnets", SPLIT_LIMIT, total),
+    })
 }
 
 pub fn format<'a>(v: &'a Value) -> Box<dyn Iterator<Item = String> + 'a> {
@@ -363,6 +1563,13 @@ pub fn format<'a>(v: &'a Value) -> Box<dyn Iterator<Item = String> + 'a> {
         Value::Unit => Box::new(iter::empty()),
         Value::V4Set(s) => Box::new(SetWalker::new(s).map(V4::from).map(|e| e.to_string())),
         Value::V6Set(s) => Box::new(SetWalker::new(s).map(V6::from).map(|e| e.to_string())),
+        Value::V4Summary(s, _) => Box::new(SetWalker::new(s).map(V4::from).map(|e| e.to_string())),
+        Value::V6Summary(s, _) => Box::new(SetWalker::new(s).map(V6::from).map(|e| e.to_string())),
+        Value::Bool(b) => Box::new(iter::once(b.to_string())),
+        Value::Number(n) => Box::new(iter::once(n.to_string())),
+        Value::V4Split(..) | Value::V6Split(..) => capped(subnets(v).unwrap()),
+        Value::Lookup(matches) => Box::new(matches.iter().map(|(name, prefix)| format!("{}: {}", name, prefix))),
+        Value::Function(f) => Box::new(iter::once(format!("fn {}({})", f.name, f.params.join(", ")))),
     }
 }
 
@@ -373,6 +1580,27 @@ pub fn eval_stmt<'a>(stmt : &Stmt<'a>, mut s: Scope) -> anyhow::Result<(Value, S
             s.bindings.insert(ident.to_string(), val_evaled);
             Ok((Value::Unit, s))
         },
+        Stmt::FnDef { ident, params, body } => {
+            let f = Function {
+                name: ident.to_string(),
+                params: params.iter().map(|p| p.to_string()).collect(),
+                body: body.to_static(),
+                scope: s.clone(),
+            };
+            s.bindings.insert(ident.to_string(), Value::Function(Rc::new(f)));
+            Ok((Value::Unit, s))
+        },
+        Stmt::Import { path, alias } => {
+            let module = import(path, &s)?;
+            for (name, val) in module.bindings {
+                let name = match alias {
+                    Some(ns) => format!("{}.{}", ns, name),
+                    None => name,
+                };
+                s.bindings.insert(name, val);
+            }
+            Ok((Value::Unit, s))
+        },
         Stmt::Expr(e) => eval_expr(e, s.clone()).map(|r| (r, s))
     }
 }
@@ -389,13 +1617,70 @@ fn eval_expr<'a>(expr: &Expr<'a>, s: Scope) -> anyhow::Result<Value> {
             let rhs = eval_expr(rhs, s)?;
             lhs.subtract(&rhs)
         }
+        Expr::Intersection(lhs, rhs) => {
+            let lhs = eval_expr(lhs, s.clone())?;
+            let rhs = eval_expr(rhs, s)?;
+            lhs.intersect(&rhs)
+        }
+        Expr::SymmetricDifference(lhs, rhs) => {
+            let lhs = eval_expr(lhs, s.clone())?;
+            let rhs = eval_expr(rhs, s)?;
+            lhs.symmetric_difference(&rhs)
+        }
+        Expr::Complement(inner) => eval_expr(inner, s)?.complement(),
+        Expr::Predicate(op, lhs, rhs) => {
+            let lhs = eval_expr(lhs, s.clone())?;
+            let rhs = eval_expr(rhs, s)?;
+            lhs.predicate(*op, &rhs)
+        }
+        Expr::LetIn { ident, val, body } => {
+            let val_evaled = eval_expr(val, s.clone())?;
+            let mut inner = s;
+            inner.bindings.insert(ident.to_string(), val_evaled);
+            eval_expr(body, inner)
+        }
+        Expr::Call { func, args, named } => {
+            let f = match s.get(func.as_ref()) {
+                Some(Value::Function(f)) => f.clone(),
+                Some(v) => return Err(anyhow!("Cannot call {}, which is a {}", func, v.type_name())),
+                None => {
+                    let b = BUILTINS.iter().find(|b| b.name == func.as_ref())
+                        .ok_or_else(|| anyhow!("Function not found in scope: {}", func))?;
+                    let args = bind_args(func, b.params, args, named)?
+                        .into_iter().map(|a| eval_expr(a, s.clone())).collect::<anyhow::Result<Vec<_>>>()?;
+                    return (b.call)(&args, &s);
+                }
+            };
+            let args = bind_args(func, &f.params, args, named)?;
+            if s.depth >= MAX_CALL_DEPTH {
+                return Err(anyhow!("Maximum call depth of {} exceeded when calling {}", MAX_CALL_DEPTH, func));
+            }
+
+            let mut inner = f.scope.clone();
+            inner.depth = s.depth + 1;
+            // Visible to itself for recursion
+            inner.bindings.insert(f.name.clone(), Value::Function(f.clone()));
+            for (param, arg) in f.params.iter().zip(args) {
+                let arg_evaled = eval_expr(arg, s.clone())?;
+                inner.bindings.insert(param.clone(), arg_evaled);
+            }
+            eval_expr(&f.body, inner)
+        }
         Expr::Atomic(a) => match a {
             Atomic::Ident(i) => {
-                let lookup = s.bindings.get(*i);
-                lookup.cloned().ok_or_else(|| anyhow!("Identifier not found in scope: {}", *i))
+                let lookup = s.get(i.as_ref());
+                lookup.cloned().ok_or_else(|| anyhow!("Identifier not found in scope: {}", i))
             }
             Atomic::V4(v) => Ok(Value::V4Set(v.into())),
             Atomic::V6(v) => Ok(Value::V6Set(v.into())),
+            Atomic::V4Range(start, end) => Ok(Value::V4Set(construct_ranges_node(&[(*start as u128, *end as u128)], 0, 0))),
+            Atomic::V6Range(start, end) => Ok(Value::V6Set(construct_ranges_node(&[(*start, *end)], 0, 0))),
+            Atomic::V4List(members) => Ok(Value::V4Set(construct_ranges_set(
+                members.iter().map(|(start, end)| (*start as u128, *end as u128)).collect()
+            ))),
+            Atomic::V6List(members) => Ok(Value::V6Set(construct_ranges_set(members.clone()))),
+            Atomic::Load { path, family } => load(path, *family, &s),
+            Atomic::Number(n) => Ok(Value::Number(*n)),
         }
     }
 }
@@ -403,9 +1688,7 @@ fn eval_expr<'a>(expr: &Expr<'a>, s: Scope) -> anyhow::Result<Value> {
 #[test]
 fn test() {
     fn eval_single<'a>(stmt: &Stmt<'a>) -> anyhow::Result<Value> {
-        eval_stmt(stmt, Scope {
-            bindings: Default::default()
-        }).map(|e| e.0)
+        eval_stmt(stmt, Scope::default()).map(|e| e.0)
     }
     use crate::parser::parse_single;
     // FIXME: write real test!
@@ -415,4 +1698,678 @@ fn test() {
     println!("{:?}", eval_single(&parse_single("0.0.0.0/1").unwrap()));
     println!("{:?}", eval_single(&parse_single("0.0.0.0/1 + 128.0.0.0/1").unwrap()));
     println!("{:?}", eval_single(&parse_single("0.0.0.0/0 - 101.6.6.6/32").unwrap()));
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+fn eval_formatted(input: &str) -> anyhow::Result<Vec<String>> {
+    let parsed = crate::parser::parse(input)?;
+    let evaled = eval(&parsed)?;
+    Ok(evaled.last().map(|v| format(v).collect()).unwrap_or_default())
+}
+
+#[cfg(test)]
+fn check(input: &str, expected: &[&str]) {
+    assert_eq!(eval_formatted(input).unwrap(), expected, "{}", input);
+}
+
+#[cfg(test)]
+fn assert_canonical(input: &str) {
+    let parsed = crate::parser::parse_single(input).unwrap();
+    match eval_stmt(&parsed, Scope::default()).unwrap().0 {
+        Value::V4Set(s) => assert!(s.is_canonical(), "{}", input),
+        Value::V6Set(s) => assert!(s.is_canonical(), "{}", input),
+        v => panic!("Expected a set, but got a {}", v.type_name()),
+    }
+}
+
+// Temporary directory with an empty main.cidr to evaluate in, removed on drop so failing tests clean up too
+#[cfg(test)]
+struct TestDir(PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str, files: &[(&str, &str)]) -> TestDir {
+        let dir = TestDir(std::env::temp_dir().join(format!("cidr-calc-test-{}-{}", name, std::process::id())));
+        for (file, content) in [("main.cidr", "")].iter().chain(files) {
+            let path = dir.0.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn run(&self, src: &str, scope: Scope) -> anyhow::Result<Vec<String>> {
+        let evaled = eval_in(&parse(src)?, scope.in_file(&self.0.join("main.cidr"))?)?;
+        Ok(evaled.last().map(|v| format(v).collect()).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_intersect() {
+    assert_eq!(
+        eval_formatted("10.0.0.0/8 & 10.1.0.0/16").unwrap(),
+        vec!["10.1.0.0/16"],
+    );
+    assert_eq!(
+        eval_formatted("10.0.0.0/8 & 11.0.0.0/8").unwrap(),
+        Vec::<String>::new(),
+    );
+    // & binds tighter than + / -
+    assert_eq!(
+        eval_formatted("1.0.0.0/8 + 10.0.0.0/8 & 10.1.0.0/16").unwrap(),
+        vec!["1.0.0.0/8", "10.1.0.0/16"],
+    );
+    assert_eq!(
+        eval_formatted("(0.0.0.0/0 - 101.6.6.6/32) & (101.6.6.0/31 + 101.6.6.6/31)").unwrap(),
+        vec!["101.6.6.0/31", "101.6.6.7/32"],
+    );
+    assert_eq!(
+        eval_formatted("let a = ::/0 - 2001:da8::/32\na & 2001::/16").unwrap(),
+        vec!["2001::/21", "2001:800::/22", "2001:c00::/24", "2001:d00::/25", "2001:d80::/27", "2001:da0::/29", "2001:da9::/32", "2001:daa::/31", "2001:dac::/30", "2001:db0::/28", "2001:dc0::/26", "2001:e00::/23", "2001:1000::/20", "2001:2000::/19", "2001:4000::/18", "2001:8000::/17"],
+    );
+    assert!(eval_formatted("::/0 & 0.0.0.0/0").is_err());
+
+    assert_canonical("(10.0.0.0/9 + 10.128.0.0/9) & 10.0.0.0/8");
+}
+
+#[test]
+fn test_symmetric_difference() {
+    assert_eq!(
+        eval_formatted("10.0.0.0/8 ^ 10.0.0.0/8").unwrap(),
+        Vec::<String>::new(),
+    );
+    assert_eq!(
+        eval_formatted("10.0.0.0/8 ^ 11.0.0.0/8").unwrap(),
+        vec!["10.0.0.0/7"],
+    );
+    assert_eq!(
+        eval_formatted("10.0.0.0/8 ^ 10.128.0.0/9").unwrap(),
+        vec!["10.0.0.0/9"],
+    );
+    assert_eq!(
+        eval_formatted("(10.0.0.0/16 + 10.2.0.0/16) ^ (10.1.0.0/16 + 10.2.0.0/16)").unwrap(),
+        vec!["10.0.0.0/15"],
+    );
+    assert_eq!(
+        eval_formatted("0.0.0.0/1 ^ 101.6.6.6/32").unwrap(),
+        eval_formatted("0.0.0.0/1 - 101.6.6.6/32").unwrap(),
+    );
+    assert!(eval_formatted("::/0 ^ 0.0.0.0/0").is_err());
+
+    assert_canonical("(10.0.0.0/9 + 11.0.0.0/8) ^ (10.128.0.0/9 + 11.0.0.0/9)");
+}
+
+#[test]
+fn test_complement() {
+    assert_eq!(
+        eval_formatted("!101.6.6.6/32").unwrap(),
+        eval_formatted("0.0.0.0/0 - 101.6.6.6/32").unwrap(),
+    );
+    assert_eq!(
+        eval_formatted("!(::/0 - 2001:da8::/56)").unwrap(),
+        vec!["2001:da8::/56"],
+    );
+    assert_eq!(
+        eval_formatted("!!10.0.0.0/8").unwrap(),
+        vec!["10.0.0.0/8"],
+    );
+    assert_eq!(
+        eval_formatted("!0.0.0.0/0").unwrap(),
+        Vec::<String>::new(),
+    );
+    assert_eq!(
+        eval_formatted("!(0.0.0.0/0 - 0.0.0.0/0)").unwrap(),
+        vec!["0.0.0.0/0"],
+    );
+    // Complement binds tighter than both & and +
+    assert_eq!(
+        eval_formatted("!0.0.0.0/1 & 192.0.0.0/2 + 0.0.0.0/1").unwrap(),
+        vec!["0.0.0.0/1", "192.0.0.0/2"],
+    );
+
+    assert_canonical("!(10.0.0.0/9 + 11.0.0.0/8)");
+}
+
+#[test]
+fn test_range() {
+    assert_eq!(
+        eval_formatted("10.0.0.5-10.0.0.77").unwrap(),
+        vec!["10.0.0.5/32", "10.0.0.6/31", "10.0.0.8/29", "10.0.0.16/28", "10.0.0.32/27", "10.0.0.64/29", "10.0.0.72/30", "10.0.0.76/31"],
+    );
+    assert_eq!(
+        eval_formatted("10.0.0.0-10.255.255.255").unwrap(),
+        vec!["10.0.0.0/8"],
+    );
+    assert_eq!(
+        eval_formatted("0.0.0.0-255.255.255.255").unwrap(),
+        vec!["0.0.0.0/0"],
+    );
+    assert_eq!(
+        eval_formatted("1.2.3.4-1.2.3.4").unwrap(),
+        vec!["1.2.3.4/32"],
+    );
+    assert_eq!(
+        eval_formatted("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff").unwrap(),
+        vec!["::/0"],
+    );
+    assert_eq!(
+        eval_formatted("2001:db8::1-2001:db8::6").unwrap(),
+        vec!["2001:db8::1/128", "2001:db8::2/127", "2001:db8::4/127", "2001:db8::6/128"],
+    );
+
+    assert_canonical("10.0.0.5-10.200.3.77");
+}
+
+#[test]
+fn test_set_literal() {
+    assert_eq!(
+        eval_formatted("{1.2.3.0/24, 5.6.0.0/16, 1.2.2.0/24,}").unwrap(),
+        vec!["1.2.2.0/23", "5.6.0.0/16"],
+    );
+    assert_eq!(
+        eval_formatted("{10.0.0.0/9, 10.128.0.0/9, 10.1.0.0/16, 10.0.0.5}").unwrap(),
+        vec!["10.0.0.0/8"],
+    );
+    // Adjacent but unaligned members
+    assert_eq!(
+        eval_formatted("{10.0.0.1-10.0.0.3, 10.0.0.4/30, 10.0.0.0}").unwrap(),
+        vec!["10.0.0.0/29"],
+    );
+    assert_eq!(
+        eval_formatted("{0.0.0.0/1, 128.0.0.0/1}").unwrap(),
+        vec!["0.0.0.0/0"],
+    );
+    assert_eq!(
+        eval_formatted("{::/1, 8000::/1, ::1}").unwrap(),
+        vec!["::/0"],
+    );
+    assert_eq!(
+        eval_formatted("{2001:da8::/32, 2001:db8::/32} - 2001:da8::/32").unwrap(),
+        vec!["2001:db8::/32"],
+    );
+
+    assert_canonical("{10.0.0.5-10.200.3.77, 10.0.0.0/24, 10.4.0.0/14, 11.0.0.0/8}");
+}
+
+#[test]
+fn test_let_expr() {
+    assert_eq!(
+        eval_formatted("let a = 10.0.0.0/8 in a - 10.128.0.0/9").unwrap(),
+        vec!["10.0.0.0/9"],
+    );
+    // Shadowing is scoped to the body
+    assert_eq!(
+        eval_formatted("let a = 10.0.0.0/8\n(let a = 11.0.0.0/8 in a) + a").unwrap(),
+        vec!["10.0.0.0/7"],
+    );
+    assert!(eval_formatted("(let a = 10.0.0.0/8 in a) + a").is_err());
+
+    let stmt = crate::parser::parse_single("let b = 10.0.0.0/8 in b").unwrap();
+    let (_, scope) = eval_stmt(&stmt, Scope::default()).unwrap();
+    assert_eq!(scope.keys().count(), 0);
+}
+
+#[test]
+fn test_fn() {
+    let example = r#"
+    let bogons = {10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16}
+    fn split_tunnel(x) = !bogons - x
+    let bogons = 0.0.0.0/0 // Doesn't affect the captured scope
+    split_tunnel(101.6.6.6)
+    "#;
+    assert_eq!(
+        eval_formatted(example).unwrap(),
+        eval_formatted("!{10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 101.6.6.6}").unwrap(),
+    );
+
+    assert_eq!(
+        eval_formatted("fn both(a, b) = a & b\nfn univ() = ::/0\nboth(univ(), 2001:da8::/32)").unwrap(),
+        vec!["2001:da8::/32"],
+    );
+    // Parameters shadow outer bindings only inside the body
+    assert_eq!(
+        eval_formatted("let x = 10.0.0.0/8\nfn id(x) = x\nid(11.0.0.0/8) + x").unwrap(),
+        vec!["10.0.0.0/7"],
+    );
+
+    let err = eval_formatted("fn f(a, b) = a\nf(10.0.0.0/8)").unwrap_err();
+    assert!(err.to_string().contains("takes 2 argument(s)"));
+    let err = eval_formatted("let a = 10.0.0.0/8\na(10.0.0.0/8)").unwrap_err();
+    assert!(err.to_string().contains("v4 set"));
+    assert!(eval_formatted("g(10.0.0.0/8)").is_err());
+    assert!(eval_formatted("fn f(a) = a\nf + 10.0.0.0/8").is_err());
+    let err = eval_formatted("fn loop(x) = loop(x) + x\nloop(10.0.0.0/8)").unwrap_err();
+    assert!(err.to_string().contains("Maximum call depth"));
+}
+
+#[test]
+fn test_import() {
+    let dir = TestDir::new("import", &[
+        ("common.cidr", "let office = 10.1.0.0/16\nfn tunnel(x) = !office - x\noffice"),
+        ("sites/a.cidr", "import \"../common.cidr\" as corp\nlet a = corp.office + 10.2.0.0/16"),
+        ("cycle_a.cidr", "import \"cycle_b.cidr\""),
+        ("cycle_b.cidr", "import \"cycle_a.cidr\""),
+    ]);
+    let run = |src: &str| dir.run(src, Scope::default());
+
+    assert_eq!(
+        run("import \"common.cidr\"\noffice").unwrap(),
+        vec!["10.1.0.0/16"],
+    );
+    // Nested imports resolve relative to the importing file
+    assert_eq!(
+        run("import \"sites/a.cidr\" as site\nsite.a - site.corp.office").unwrap(),
+        vec!["10.2.0.0/16"],
+    );
+    assert_eq!(
+        run("import \"common.cidr\" as c\nc.tunnel(0.0.0.0/1)").unwrap(),
+        vec!["128.0.0.0/1"],
+    );
+    assert!(run("import \"common.cidr\" as c\noffice").is_err());
+    let err = run("import \"cycle_a.cidr\"").unwrap_err();
+    assert!(err.to_string().contains("Import cycle"));
+    assert!(run("import \"missing.cidr\"").is_err());
+}
+
+#[test]
+fn test_load() {
+    let dir = TestDir::new("load", &[
+        ("v4.txt", "# Some list\n10.0.0.0/9\n\n10.128.0.0/9 // second half\n192.168.1.1\r\n"),
+        ("mixed.txt", "10.0.0.0/8\n2001:da8::/32\n::1\n"),
+        ("bad.txt", "10.0.0.0/8\n\n10.0.0.0/33\n"),
+        ("junk.txt", "10.0.0.0/8\nexample.com\n"),
+    ]);
+    let run = |src: &str| dir.run(src, Scope::default());
+
+    assert_eq!(
+        run("load(\"v4.txt\")").unwrap(),
+        vec!["10.0.0.0/8", "192.168.1.1/32"],
+    );
+    assert_eq!(
+        run("load(\"mixed.txt\", v4) + 11.0.0.0/8").unwrap(),
+        vec!["10.0.0.0/7"],
+    );
+    assert_eq!(
+        run("load(\"mixed.txt\", v6)").unwrap(),
+        vec!["::1/128", "2001:da8::/32"],
+    );
+    let err = run("load(\"mixed.txt\")").unwrap_err().to_string();
+    assert!(err.contains("line 1") && err.contains("line 2"));
+    let err = run("load(\"bad.txt\")").unwrap_err().to_string();
+    assert!(err.contains("bad.txt:3"));
+    let err = run("load(\"junk.txt\")").unwrap_err().to_string();
+    assert!(err.contains("junk.txt:2"));
+    assert!(run("load(\"missing.txt\")").is_err());
+}
+
+#[test]
+fn test_prelude() {
+    assert_eq!(
+        eval_formatted("rfc1918 & 10.1.0.0/16").unwrap(),
+        vec!["10.1.0.0/16"],
+    );
+    assert_eq!(
+        eval_formatted("documentation_v6").unwrap(),
+        vec!["2001:db8::/32", "3fff::/20"],
+    );
+    assert_eq!(
+        eval_formatted("bogons_v4 & 100.0.0.0/8").unwrap(),
+        vec!["100.64.0.0/10"],
+    );
+    assert_eq!(
+        eval_formatted("bogons_v6 & ::ffff:0:0/96").unwrap(),
+        vec!["::ffff:0:0/96"],
+    );
+    // User bindings shadow the prelude
+    assert_eq!(
+        eval_formatted("let rfc1918 = 10.0.0.0/8\nrfc1918").unwrap(),
+        vec!["10.0.0.0/8"],
+    );
+    assert!(eval_in(&crate::parser::parse("rfc1918").unwrap(), Scope::default()).is_err());
+
+    let scope = Scope::prelude();
+    for name in ["rfc1918", "cgnat", "loopback_v4", "loopback_v6", "link_local_v4", "link_local_v6", "documentation_v4",
+        "documentation_v6", "multicast_v4", "multicast_v6", "six_to_four_v4", "six_to_four_v6", "teredo", "ula",
+        "bogons_v4", "bogons_v6"] {
+        assert!(scope.keys().any(|k| k == name), "{} missing from prelude", name);
+    }
+}
+
+#[test]
+fn test_strict() {
+    let dir = TestDir::new("strict", &[
+        ("common.cidr", "let office = 10.1.2.3/16"),
+        ("list.txt", "10.0.0.0/8\n\n192.168.1.1/24\n"),
+    ]);
+
+    let scope = Scope::default();
+    let output = dir.run("import \"common.cidr\"\noffice + load(\"list.txt\")", scope.clone()).unwrap();
+    assert_eq!(output, vec!["10.0.0.0/8", "192.168.1.0/24"]);
+    let warnings = scope.take_warnings();
+    assert_eq!(warnings.len(), 2);
+    assert_eq!(warnings[0].file, Some(dir.0.join("common.cidr").canonicalize().unwrap()));
+    assert_eq!(warnings[0].pos, Some((1, 14)));
+    assert_eq!(warnings[1].file, Some(dir.0.join("list.txt").canonicalize().unwrap()));
+    assert_eq!(warnings[1].pos, Some((3, 1)));
+
+    let strict = Scope::default().with_options(ParseOptions { strict: true });
+    assert!(dir.run("import \"common.cidr\"", strict.clone()).is_err());
+    let err = dir.run("load(\"list.txt\")", strict).unwrap_err().to_string();
+    assert!(err.contains("list.txt:3"));
+}
+
+#[test]
+fn test_predicate() {
+    check("10.0.0.0/8 contains 10.2.0.0/16", &["true"]);
+    check("10.2.0.0/16 contains 10.0.0.0/8", &["false"]);
+    check("10.2.0.0/16 subset 10.0.0.0/8", &["true"]);
+    check("10.0.0.0/8 - 10.2.3.0/24 contains 10.2.0.0/16", &["false"]);
+    check("(10.0.0.0/9 + 10.128.0.0/9) subset 10.0.0.0/8", &["true"]);
+    check("0.0.0.0/0 - 0.0.0.0/0 subset 10.0.0.0/8", &["true"]);
+    check("10.0.0.0/8 overlaps 10.2.3.4", &["true"]);
+    check("10.0.0.0/8 overlaps 11.0.0.0/8", &["false"]);
+    check("!10.0.0.0/8 overlaps 10.255.255.255", &["false"]);
+    check("{10.0.0.0/9, 10.128.0.0/9} == 10.0.0.0/8", &["true"]);
+    check("10.0.0.0-10.0.0.255 == 10.0.0.0/24", &["true"]);
+    check("10.0.0.0/8 == 10.0.0.0/9", &["false"]);
+    check("::/0 != !2001:db8::/32", &["true"]);
+    check("let pool = rfc1918 in 10.2.0.0/16 subset pool", &["true"]);
+
+    assert!(eval_formatted("10.0.0.0/8 contains ::1").is_err());
+    assert!(eval_formatted("(10.0.0.0/8 == 10.0.0.0/8) + 10.0.0.0/8").is_err());
+}
+
+#[test]
+fn test_count_size() {
+    check("count(10.0.0.0/8)", &["1"]);
+    check("size(10.0.0.0/8)", &["16777216"]);
+    check("count(0.0.0.0/0 - 10.0.0.0/8)", &["8"]);
+    check("size(0.0.0.0/0 - 10.0.0.0/8)", &["4278190080"]);
+    check("size(0.0.0.0/0)", &["4294967296"]);
+    check("count(10.0.0.0/8 - 10.0.0.0/8)", &["0"]);
+    check("size(10.0.0.0/8 - 10.0.0.0/8)", &["0"]);
+    check("count(10.0.0.1-10.0.0.6)", &["4"]);
+    check("size(10.0.0.1-10.0.0.6)", &["6"]);
+    check("size(::/0)", &["340282366920938463463374607431768211456"]);
+    check("size(::/1)", &["170141183460469231731687303715884105728"]);
+    check("size(::/0 - ::1)", &["340282366920938463463374607431768211455"]);
+    check("count(::/0 - ::1)", &["128"]);
+    check("size(2001:db8::/32)", &["79228162514264337593543950336"]);
+
+    assert!(eval_formatted("count(fn_missing)").is_err());
+    assert!(eval_formatted("size(10.0.0.0/8, 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("size(10.0.0.0/8 == 10.0.0.0/8)").is_err());
+    // User bindings shadow builtins
+    assert_eq!(eval_formatted("fn size(x) = x\nsize(10.0.0.0/8)").unwrap(), vec!["10.0.0.0/8"]);
+}
+
+#[test]
+fn test_filter_len() {
+    let set = "let x = {10.0.0.0/8, 172.16.0.0/12, 192.168.1.0/24, 192.168.2.128/25, 1.2.3.4}";
+    let filtered = eval_formatted(&format!("{}\nfilter_len(x, 9, 24)", set)).unwrap();
+    assert_eq!(filtered, vec!["172.16.0.0/12", "192.168.1.0/24"]);
+    let filtered = eval_formatted(&format!("{}\nfilter_len(x, 0, 32) == x", set)).unwrap();
+    assert_eq!(filtered, vec!["true"]);
+    let filtered = eval_formatted(&format!("{}\nfilter_len(x, 25, 32) + 10.0.0.0/8", set)).unwrap();
+    assert_eq!(filtered, vec!["1.2.3.4/32", "10.0.0.0/8", "192.168.2.128/25"]);
+
+    let filtered = eval_formatted("filter_len(::/0 - ::1, 1, 64)").unwrap();
+    assert_eq!(filtered.len(), 64);
+    assert_eq!(filtered[63], "8000::/1");
+    assert!(eval_formatted("filter_len(::/0, 1, 128)").unwrap().is_empty());
+
+    assert!(eval_formatted("filter_len(10.0.0.0/8, 8, 33)").is_err());
+    assert!(eval_formatted("filter_len(10.0.0.0/8, 24, 8)").is_err());
+    assert!(eval_formatted("filter_len(10.0.0.0/8, 8)").is_err());
+    assert!(eval_formatted("filter_len(8, 8, 8)").is_err());
+    assert!(eval_formatted("10.0.0.0/8 + 8").is_err());
+}
+
+#[test]
+fn test_split() {
+    let split = eval_formatted("split(10.0.0.0/16 - 10.0.5.0/24, 24)").unwrap();
+    assert_eq!(split.len(), 255);
+    assert_eq!(&split[..6], ["10.0.0.0/24", "10.0.1.0/24", "10.0.2.0/24", "10.0.3.0/24", "10.0.4.0/24", "10.0.6.0/24"]);
+    assert_eq!(split[254], "10.0.255.0/24");
+
+    // Longer prefixes are kept as is
+    let split = eval_formatted("split({10.0.0.0/23, 10.1.0.0/25, 10.2.0.1}, 24)").unwrap();
+    assert_eq!(split, vec!["10.0.0.0/24", "10.0.1.0/24", "10.1.0.0/25", "10.2.0.1/32"]);
+    assert_eq!(eval_formatted("split(0.0.0.0/0, 0)").unwrap(), vec!["0.0.0.0/0"]);
+    assert_eq!(eval_formatted("split(0.0.0.0/0, 1)").unwrap(), vec!["0.0.0.0/1", "128.0.0.0/1"]);
+    assert_eq!(eval_formatted("split(255.255.255.254/31, 32)").unwrap(), vec!["255.255.255.254/32", "255.255.255.255/32"]);
+    assert!(eval_formatted("split(10.0.0.0/8 - 10.0.0.0/8, 24)").unwrap().is_empty());
+
+    let split = eval_formatted("split(::/0, 64)").unwrap();
+    assert_eq!(split.len(), SPLIT_LIMIT);
+    assert_eq!(split[1], "0:0:0:1::/64");
+    assert_eq!(split[SPLIT_LIMIT - 1], "0:0:0:ffff::/64");
+    let truncated = |input: &str| eval(&parse(input).unwrap()).unwrap().last().and_then(truncation_warning).map(|w| w.to_string());
+    assert_eq!(truncated("split(::/0, 64)").unwrap(), "Showing only the first 65536 of 18446744073709551616 subnets");
+    assert_eq!(truncated("hosts(::/0)").unwrap(), "Showing only the first 65536 of 340282366920938463463374607431768211456 subnets");
+    assert_eq!(truncated("split(10.0.0.0/8, 24)"), None);
+    assert!(truncated("split(10.0.0.0/8, 25)").is_some());
+    assert_eq!(truncated("split(10.0.0.0/8, 24) + 10.0.0.0/8"), None);
+
+    // Uncapped for library callers
+    let evaled = eval(&parse("split(::/0, 64)").unwrap()).unwrap();
+    assert_eq!(subnets(&evaled[0]).unwrap().nth(SPLIT_LIMIT).unwrap(), "0:0:1::/64");
+    let evaled = eval(&parse("split(10.0.0.0/8, 26)").unwrap()).unwrap();
+    assert_eq!(subnets(&evaled[0]).unwrap().count(), 262144);
+    assert!(subnets(&eval(&parse("10.0.0.0/8").unwrap()).unwrap()[0]).is_none());
+
+    assert!(eval_formatted("split(10.0.0.0/8, 33)").is_err());
+    assert_eq!(eval_formatted("split(10.0.0.0/8, 24) + 10.0.0.0/8").unwrap(), vec!["10.0.0.0/8"]);
+}
+
+#[test]
+fn test_summarize() {
+    let run = |input: &str| -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let scope = Scope::prelude();
+        let evaled = eval_in(&parse(input)?, scope.clone())?;
+        let warnings = scope.take_warnings().iter().map(|w| w.to_string()).collect();
+        Ok((evaled.last().map(|v| format(v).collect()).unwrap_or_default(), warnings))
+    };
+
+    // The 32 prefixes of remove_single_reverse.txt
+    let (output, warnings) = run("summarize(0.0.0.0/0 - 101.6.6.6, max_prefixes = 1)").unwrap();
+    assert_eq!(output, vec!["0.0.0.0/0"]);
+    assert_eq!(warnings, vec!["summarize() added 1 extra addresses"]);
+    // Same extra addresses, so fewer prefixes win
+    let (output, _) = run("summarize(0.0.0.0/0 - 101.6.6.6, max_prefixes = 4)").unwrap();
+    assert_eq!(output, vec!["0.0.0.0/0"]);
+
+    // Merges the siblings with the fewest extra addresses
+    let (output, warnings) = run("summarize({10.0.0.0/24, 10.0.1.0/25, 10.0.8.0/24, 10.0.9.128/25, 10.1.0.0/16}, max_prefixes = 3)").unwrap();
+    assert_eq!(output, vec!["10.0.0.0/23", "10.0.8.0/23", "10.1.0.0/16"]);
+    assert_eq!(warnings, vec!["summarize() added 256 extra addresses"]);
+    let (output, _) = run("summarize({10.0.0.0/24, 10.0.1.0/25, 10.0.8.0/24, 10.0.9.128/25, 10.1.0.0/16}, max_prefixes = 2)").unwrap();
+    assert_eq!(output, vec!["10.0.0.0/20", "10.1.0.0/16"]);
+
+    // Already small enough
+    let (output, warnings) = run("summarize(rfc1918, 3)").unwrap();
+    assert_eq!(output, vec!["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"]);
+    assert!(warnings.is_empty());
+    let (output, _) = run("summarize(::/0 - ::/0, 0)").unwrap();
+    assert!(output.is_empty());
+    let (output, warnings) = run("summarize(::1 + (::/1 - ::/2), max_prefixes = 1)").unwrap();
+    assert_eq!(output, vec!["::/1"]);
+    assert_eq!(warnings, vec!["summarize() added 85070591730234615865843651857942052863 extra addresses"]);
+
+    // Kept with the value too, for callers without the scope
+    match eval(&parse("summarize(0.0.0.0/0 - 101.6.6.6, 1)").unwrap()).unwrap().last() {
+        Some(Value::V4Summary(_, extra)) => assert_eq!(*extra, 1),
+        v => panic!("Expected a v4 summary, but got {:?}", v),
+    }
+    match eval(&parse("summarize(::1 + ::3, 1)").unwrap()).unwrap().last() {
+        Some(Value::V6Summary(_, extra)) => assert_eq!(*extra, 2),
+        v => panic!("Expected a v6 summary, but got {:?}", v),
+    }
+    assert_eq!(eval_formatted("count(summarize(rfc1918, 2))").unwrap(), vec!["2"]);
+
+    // Large sets that already fit come back as they are, without planning
+    let spread = (0..16384).map(|i| construct_set_node::<32>(0x0a00_0000 + i * 4, 32, 0))
+        .fold(SetNode { depth: 0, covered: false, left: None, right: None }, |s, p| s.union(&p));
+    assert_eq!(spread.summarize(100000), Some((spread.clone(), 0)));
+    assert_eq!(spread.summarize(16384), Some((spread.clone(), 0)));
+
+    let summarized = eval_formatted("let x = 0.0.0.0/0 - 101.6.6.6 - 1.2.3.4 in summarize(x, max_prefixes = 10) contains x").unwrap();
+    assert_eq!(summarized, vec!["true"]);
+    assert!(eval_formatted("summarize(10.0.0.0/8, 0)").is_err());
+    assert!(eval_formatted("summarize(10.0.0.0/8, max = 1)").is_err());
+    assert!(eval_formatted("summarize(10.0.0.0/8, 1, max_prefixes = 1)").is_err());
+    assert!(eval_formatted("summarize(max_prefixes = 1, x = 10.0.0.0/8)").is_ok());
+    assert!(eval_formatted("fn f(a, b) = a - b\nf(b = 10.0.0.0/9, a = 10.0.0.0/8) == 10.128.0.0/9").is_ok());
+}
+
+#[test]
+fn test_coarsen_interior() {
+    let coarsened = eval_formatted("coarsen(0.0.0.0/0 - 101.6.6.6, 24)").unwrap();
+    assert_eq!(coarsened, vec!["0.0.0.0/0"]);
+    let coarsened = eval_formatted("coarsen({10.0.0.0/8, 192.168.1.1, 192.168.1.200, 192.168.3.0/25}, 24)").unwrap();
+    assert_eq!(coarsened, vec!["10.0.0.0/8", "192.168.1.0/24", "192.168.3.0/24"]);
+    let coarsened = eval_formatted("coarsen({2001:db8::1, 2001:db8:0:1::/64}, 48)").unwrap();
+    assert_eq!(coarsened, vec!["2001:db8::/48"]);
+
+    let interior = eval_formatted("interior(0.0.0.0/0 - 101.6.6.6, 24)").unwrap();
+    assert_eq!(interior.len(), 24);
+    assert!(!interior.contains(&"101.6.6.0/24".to_string()));
+    let interior = eval_formatted("interior({10.0.0.0/8, 192.168.1.1, 192.168.2.0/23, 192.168.4.0/25}, 24)").unwrap();
+    assert_eq!(interior, vec!["10.0.0.0/8", "192.168.2.0/23"]);
+
+    // Over- and under-covering
+    for input in [
+        "let x = !bogons_v4 in interior(x, 20) subset x",
+        "let x = !bogons_v4 in coarsen(x, 20) contains x",
+        "let x = !bogons_v4 in coarsen(x, 20) == filter_len(coarsen(x, 20), 0, 20)",
+        "let x = !bogons_v4 in interior(x, 20) == filter_len(x, 0, 20)",
+        "let x = !bogons_v6 in coarsen(x, 48) - interior(x, 48) == coarsen(x - interior(x, 48), 48)",
+    ] {
+        assert_eq!(eval_formatted(input).unwrap(), vec!["true"], "{}", input);
+    }
+
+    assert!(eval_formatted("coarsen(10.0.0.0/8, 33)").is_err());
+    assert!(eval_formatted("interior(true, 8)").is_err());
+}
+
+#[test]
+fn test_lookup() {
+    let input = "let office = {10.1.0.0/16, 101.6.0.0/16}\nlet tunnel = 101.6.6.0/24\nlookup(101.6.6.6)";
+    assert_eq!(eval_formatted(input).unwrap(), vec!["tunnel: 101.6.6.0/24", "office: 101.6.0.0/16"]);
+
+    let input = "let private = rfc1918 - 10.2.0.0/16\nlookup(10.1.2.3)";
+    assert_eq!(eval_formatted(input).unwrap(), vec!["private: 10.0.0.0/15", "bogons_v4: 10.0.0.0/8", "rfc1918: 10.0.0.0/8"]);
+    assert_eq!(eval_formatted("let private = rfc1918 - 10.2.0.0/16\nlookup(10.2.3.4)").unwrap(), vec!["bogons_v4: 10.0.0.0/8", "rfc1918: 10.0.0.0/8"]);
+
+    // Prefixes match only if fully covered
+    assert_eq!(eval_formatted("lookup(2001:db8::/48)").unwrap(), vec!["bogons_v6: 2001:db8::/32", "documentation_v6: 2001:db8::/32"]);
+    assert_eq!(eval_formatted("lookup(10.0.0.0/7)").unwrap(), Vec::<String>::new());
+    assert_eq!(eval_formatted("let univ = ::/0\nlookup(2400::1)").unwrap(), vec!["univ: ::/0"]);
+
+    assert!(eval_formatted("lookup(10.0.0.1-10.0.0.2)").is_err());
+    assert!(eval_in(&parse("lookup(10.0.0.1-10.0.0.2)").unwrap(), Scope::default()).is_err());
+    assert!(eval_formatted("lookup(8)").is_err());
+}
+
+#[test]
+fn test_nth() {
+    check("first(10.0.0.8/29)", &["10.0.0.8/32"]);
+    check("last(10.0.0.8/29)", &["10.0.0.15/32"]);
+    check("nth(10.0.0.8/29, 1)", &["10.0.0.9/32"]);
+    check("nth(10.0.0.8/29 - 10.0.0.8, 0)", &["10.0.0.9/32"]);
+    check("let x = {10.0.0.0/30, 10.0.1.0/24, 10.2.0.0/16} in nth(x, 3)", &["10.0.0.3/32"]);
+    check("let x = {10.0.0.0/30, 10.0.1.0/24, 10.2.0.0/16} in nth(x, 4)", &["10.0.1.0/32"]);
+    check("let x = {10.0.0.0/30, 10.0.1.0/24, 10.2.0.0/16} in nth(x, 260)", &["10.2.0.0/32"]);
+    check("let x = {10.0.0.0/30, 10.0.1.0/24, 10.2.0.0/16} in nth(x, 65795)", &["10.2.255.255/32"]);
+    check("last({10.0.0.0/30, 10.0.1.0/24, 10.2.0.0/16})", &["10.2.255.255/32"]);
+    check("last(0.0.0.0/0)", &["255.255.255.255/32"]);
+    check("first(::/0)", &["::/128"]);
+    check("last(::/0)", &["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128"]);
+    check("nth(::/0, 340282366920938463463374607431768211455)", &["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128"]);
+    check("nth(::/0 - ::/1, 170141183460469231731687303715884105727)", &["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128"]);
+    check("nth(2001:db8::/32 + 2001:db9::/48, 79228162514264337593543950336)", &["2001:db9::/128"]);
+
+    check("hosts(10.0.0.8/30 + 10.0.0.1)", &["10.0.0.1/32", "10.0.0.8/32", "10.0.0.9/32", "10.0.0.10/32", "10.0.0.11/32"]);
+    assert_eq!(eval_formatted("hosts(2001:db8::/64)").unwrap().len(), SPLIT_LIMIT);
+
+    assert!(eval_formatted("first(10.0.0.0/8 - 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("last(::/0 - ::/0)").is_err());
+    assert!(eval_formatted("nth(10.0.0.8/29, 8)").is_err());
+    assert!(eval_formatted("nth(0.0.0.0/0, 4294967296)").is_err());
+    assert!(eval_formatted("nth(10.0.0.0/8, 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("hosts(8)").is_err());
+}
+
+#[test]
+fn test_offset() {
+    check("next(10.0.0.0/24)", &["10.0.1.0/24"]);
+    check("prev(10.0.1.0/24)", &["10.0.0.0/24"]);
+    check("next(10.0.255.0/24)", &["10.1.0.0/24"]);
+    check("offset(2001:db8::/48, 5)", &["2001:db8:5::/48"]);
+    check("offset(2001:db8:5::/48, -5)", &["2001:db8::/48"]);
+    check("offset(10.0.0.0/8, 0)", &["10.0.0.0/8"]);
+    check("next(10.0.0.1)", &["10.0.0.2/32"]);
+    check("next(127.0.0.0/8) + 10.0.0.0/8 - 128.0.0.0/16", &["10.0.0.0/8", "128.1.0.0/16", "128.2.0.0/15", "128.4.0.0/14",
+        "128.8.0.0/13", "128.16.0.0/12", "128.32.0.0/11", "128.64.0.0/10", "128.128.0.0/9"]);
+    check("next(0.0.0.0/1)", &["128.0.0.0/1"]);
+    check("offset(::/128, 340282366920938463463374607431768211455)", &["ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff/128"]);
+    check("offset(ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff, -340282366920938463463374607431768211455)", &["::/128"]);
+
+    let err = eval_formatted("next(255.255.255.0/24)").unwrap_err();
+    assert_eq!(err.to_string(), "Cannot move 255.255.255.0/24 by 1 blocks, past the end of the v4 space");
+    let err = eval_formatted("prev(::/1)").unwrap_err();
+    assert_eq!(err.to_string(), "Cannot move ::/1 by -1 blocks, past the start of the v6 space");
+    assert!(eval_formatted("next(0.0.0.0/0)").is_err());
+    assert!(eval_formatted("offset(::1, 340282366920938463463374607431768211455)").is_err());
+    assert!(eval_formatted("next(10.0.0.0/24 + 10.0.2.0/24)").is_err());
+    assert!(eval_formatted("next(10.0.0.0/8 - 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("offset(10.0.0.0/8, 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("summarize(10.0.0.0/8, -1)").is_err());
+    assert!(eval_formatted("nth(10.0.0.0/8, -1)").is_err());
+}
+
+#[test]
+fn test_navigation() {
+    check("parent(10.1.2.0/24, 1)", &["10.1.2.0/23"]);
+    check("parent(10.1.2.0/24, 16)", &["10.0.0.0/8"]);
+    check("parent(10.1.2.0/24, 24)", &["0.0.0.0/0"]);
+    check("parent(10.1.2.3, 0)", &["10.1.2.3/32"]);
+    check("parent(2001:db8:1::/48, 16)", &["2001:db8::/32"]);
+    check("sibling(10.1.2.0/24)", &["10.1.3.0/24"]);
+    check("sibling(10.1.3.0/24)", &["10.1.2.0/24"]);
+    check("sibling(128.0.0.0/1)", &["0.0.0.0/1"]);
+    check("sibling(::1)", &["::/128"]);
+    check("sibling(10.1.2.0/24) + 10.1.2.0/24 == parent(10.1.2.0/24, 1)", &["true"]);
+    check("children(10.1.2.0/24, 25)", &["10.1.2.0/25", "10.1.2.128/25"]);
+    check("children(10.1.2.0/24, 26)", &["10.1.2.0/26", "10.1.2.64/26", "10.1.2.128/26", "10.1.2.192/26"]);
+    check("children(10.1.2.0/24, 24)", &["10.1.2.0/24"]);
+    check("children(2001:db8::/32, 33)", &["2001:db8::/33", "2001:db8:8000::/33"]);
+
+    let err = eval_formatted("parent(10.0.0.0/24 + 10.0.2.0/24, 1)").unwrap_err();
+    assert_eq!(err.to_string(), "Expected a single prefix, but got a set of 2 prefixes");
+    let err = eval_formatted("parent(10.0.0.0/8, 9)").unwrap_err();
+    assert_eq!(err.to_string(), "10.0.0.0/8 has no parent 9 levels up");
+    assert!(eval_formatted("sibling(::/0)").is_err());
+    assert!(eval_formatted("sibling(10.0.0.0/8 - 10.0.0.0/8)").is_err());
+    assert!(eval_formatted("children(10.1.2.0/24, 23)").is_err());
+    assert!(eval_formatted("children(10.1.2.0/24, 33)").is_err());
+    assert!(eval_formatted("children(10.1.2.0/24 + 10.1.4.0/24, 25)").is_err());
+
+    // Subnet lists work wherever a set is expected
+    check("children(10.1.2.0/24, 25) + 10.1.3.0/24", &["10.1.2.0/23"]);
+    check("children(10.1.2.0/24, 25) - 10.1.2.128/25", &["10.1.2.0/25"]);
+    check("children(10.1.2.0/24, 25) & 10.1.2.0/26", &["10.1.2.0/26"]);
+    check("children(10.1.2.0/24, 25) == 10.1.2.0/24", &["true"]);
+    check("first(split(10.1.2.0/24, 26) - 10.1.2.0/25)", &["10.1.2.128/32"]);
+    check("count(children(10.1.2.0/24, 26))", &["4"]);
+    check("count(split(10.0.0.0/23 + 10.0.4.0/25, 24))", &["3"]);
+    check("count(hosts(::/0))", &["340282366920938463463374607431768211456"]);
+    check("size(children(10.1.2.0/24, 26))", &["256"]);
+}
@@ -1,30 +1,54 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use cidr_calculator::eval::{eval, format, Value, eval_stmt, Scope};
-use cidr_calculator::parser::{parse, parse_single};
+use cidr_calculator::eval::{eval_in, format, truncation_warning, Value, eval_stmt, Scope};
+use cidr_calculator::parser::{parse_with, parse_single_with, ParseOptions, Warning};
 use rustyline::DefaultEditor;
 
 #[derive(Parser)]
 struct Args {
     input: Option<PathBuf>, // TODO: option, none is repl
+
+    /// Don't bind the built-in special-purpose address sets (rfc1918, bogons_v4, ...)
+    #[arg(long)]
+    no_prelude: bool,
+
+    /// Reject prefixes with host bits set beyond the prefix length (e.g. 10.1.2.3/16), instead of warning
+    #[arg(long)]
+    strict: bool,
+}
+
+fn print_warnings(warnings: impl IntoIterator<Item = Warning>) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    let scope = if args.no_prelude { Scope::default() } else { Scope::prelude() };
+    let options = ParseOptions { strict: args.strict };
+    let scope = scope.with_options(options);
     if args.input.is_none() {
-        return repl();
+        return repl(scope, options);
     }
 
     let input = args.input.unwrap();
     let content = std::fs::read_to_string(&input)?;
-    let parsed = parse(&content)?;
-    let evaled = eval(&parsed)?;
+    let mut warnings = Vec::new();
+    let parsed = parse_with(&content, options, &mut warnings)?;
+    print_warnings(warnings.into_iter().map(|w| Warning { file: Some(input.clone()), ..w }));
+    let evaled = eval_in(&parsed, scope.clone().in_file(&input)?);
+    print_warnings(scope.take_warnings());
+    let evaled = evaled?;
     for value in evaled {
         match value {
             Value::Unit => {},
+            Value::Bool(b) => println!("{}", b),
+            Value::Number(n) => println!("{}", n),
             _ => {
-                println!("[{}]", format(&value).collect::<Vec<_>>().join(","))
+                println!("[{}]", format(&value).collect::<Vec<_>>().join(","));
+                print_warnings(truncation_warning(&value));
             }
         }
     }
@@ -32,10 +56,9 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn repl() -> anyhow::Result<()> {
+fn repl(mut scope: Scope, options: ParseOptions) -> anyhow::Result<()> {
     let mut rl = DefaultEditor::new()?;
 
-    let mut scope = Scope::default();
     loop {
         let line = rl.readline("> ");
         match line {
@@ -43,9 +66,18 @@ fn repl() -> anyhow::Result<()> {
                 if line == "/s" {
                     println!("In scope: {}", scope.keys().collect::<Vec<_>>().join(", "));
                 } else {
+                    // `/l addr`, which sets cover the address
+                    let line = match line.strip_prefix("/l ") {
+                        Some(addr) => format!("lookup({})", addr),
+                        None => line,
+                    };
                     let evaled: anyhow::Result<_> = (|| {
-                        let stmt = parse_single(&line)?;
-                        let (v, s) = eval_stmt(&stmt, scope.clone())?;
+                        let mut warnings = Vec::new();
+                        let stmt = parse_single_with(&line, options, &mut warnings)?;
+                        print_warnings(warnings);
+                        let evaled = eval_stmt(&stmt, scope.clone());
+                        print_warnings(scope.take_warnings());
+                        let (v, s) = evaled?;
                         scope = s;
                         Ok(v)
                     })();
@@ -53,12 +85,19 @@ fn repl() -> anyhow::Result<()> {
                         Ok(Value::Unit) => {
                             continue;
                         }
+                        Ok(Value::Bool(b)) => {
+                            println!("{}", b);
+                        }
+                        Ok(Value::Number(n)) => {
+                            println!("{}", n);
+                        }
                         Ok(v) => {
                             println!("[");
                             for l in format(&v) {
                                 println!("\t{}", l);
                             }
                             println!("]");
+                            print_warnings(truncation_warning(&v));
                         }
                         Err(e) => {
                             println!("Evaluation error:");
@@ -1,4 +1,6 @@
-use pest::{Parser, iterators::Pair};
+use std::{borrow::Cow, fmt, path::PathBuf};
+
+use pest::{Parser, iterators::{Pair, Pairs}};
 use pest_derive::Parser;
 use anyhow::anyhow;
 
@@ -8,12 +10,51 @@ use crate::data::*;
 #[grammar="./syntax.pest"]
 struct SrcParser;
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    // Reject prefixes with host bits set beyond the prefix length, instead of warning
+    pub strict: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    // None for the input given to the parser directly
+    pub file: Option<PathBuf>,
+    // Line and column, None for warnings raised during evaluation
+    pub pos: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if let Some((line, col)) = self.pos {
+            write!(f, "{}:{}:", line, col)?;
+        }
+        if self.file.is_some() || self.pos.is_some() {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Stmt<'a> {
     LetIn {
         ident: &'a str,
         val: Box<Expr<'a>>,
     },
+    FnDef {
+        ident: &'a str,
+        params: Vec<&'a str>,
+        body: Box<Expr<'a>>,
+    },
+    Import {
+        path: &'a str,
+        alias: Option<&'a str>,
+    },
     Expr(Expr<'a>)
 }
 
@@ -23,93 +64,218 @@ impl<'a> From<Expr<'a>> for Stmt<'a> {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Predicate {
+    Contains,
+    Subset,
+    Overlaps,
+    Equal,
+    NotEqual,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Expr<'a> {
     Addition(Box<Expr<'a>>, Box<Expr<'a>>),
     Subtraction(Box<Expr<'a>>, Box<Expr<'a>>),
+    Intersection(Box<Expr<'a>>, Box<Expr<'a>>),
+    SymmetricDifference(Box<Expr<'a>>, Box<Expr<'a>>),
+    Complement(Box<Expr<'a>>),
+    Predicate(Predicate, Box<Expr<'a>>, Box<Expr<'a>>),
+    LetIn {
+        ident: Cow<'a, str>,
+        val: Box<Expr<'a>>,
+        body: Box<Expr<'a>>,
+    },
+    Call {
+        func: Cow<'a, str>,
+        args: Vec<Expr<'a>>,
+        // Bound by parameter name, always after the positional ones
+        named: Vec<(Cow<'a, str>, Expr<'a>)>,
+    },
     Atomic(Atomic<'a>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Atomic<'a> {
-    Ident(&'a str),
+    Ident(Cow<'a, str>),
     V4(V4),
     V6(V6),
+    V4Range(u32, u32),
+    V6Range(u128, u128),
+    V4List(Vec<(u32, u32)>),
+    V6List(Vec<(u128, u128)>),
+    Load {
+        path: Cow<'a, str>,
+        family: Option<Family>,
+    },
+    Number(Number),
 }
 
-fn process_v6_half(half: &str) -> (u128, u8) {
-    if half.len() == 0 {
+impl Expr<'_> {
+    // Detach from the source text, e.g. for function bodies outliving a REPL line
+    pub fn to_static(&self) -> Expr<'static> {
+        let boxed = |e: &Expr<'_>| Box::new(e.to_static());
+        match self {
+            Expr::Addition(lhs, rhs) => Expr::Addition(boxed(lhs), boxed(rhs)),
+            Expr::Subtraction(lhs, rhs) => Expr::Subtraction(boxed(lhs), boxed(rhs)),
+            Expr::Intersection(lhs, rhs) => Expr::Intersection(boxed(lhs), boxed(rhs)),
+            Expr::SymmetricDifference(lhs, rhs) => Expr::SymmetricDifference(boxed(lhs), boxed(rhs)),
+            Expr::Complement(inner) => Expr::Complement(boxed(inner)),
+            Expr::Predicate(op, lhs, rhs) => Expr::Predicate(*op, boxed(lhs), boxed(rhs)),
+            Expr::LetIn { ident, val, body } => Expr::LetIn {
+                ident: Cow::Owned(ident.to_string()),
+                val: boxed(val),
+                body: boxed(body),
+            },
+            Expr::Call { func, args, named } => Expr::Call {
+                func: Cow::Owned(func.to_string()),
+                args: args.iter().map(Expr::to_static).collect(),
+                named: named.iter().map(|(name, arg)| (Cow::Owned(name.to_string()), arg.to_static())).collect(),
+            },
+            Expr::Atomic(a) => Expr::Atomic(match a {
+                Atomic::Ident(i) => Atomic::Ident(Cow::Owned(i.to_string())),
+                Atomic::V4(v) => Atomic::V4(V4(v.0, v.1)),
+                Atomic::V6(v) => Atomic::V6(V6(v.0, v.1)),
+                Atomic::V4Range(start, end) => Atomic::V4Range(*start, *end),
+                Atomic::V6Range(start, end) => Atomic::V6Range(*start, *end),
+                Atomic::V4List(members) => Atomic::V4List(members.clone()),
+                Atomic::V6List(members) => Atomic::V6List(members.clone()),
+                Atomic::Load { path, family } => Atomic::Load {
+                    path: Cow::Owned(path.to_string()),
+                    family: *family,
+                },
+                Atomic::Number(n) => Atomic::Number(*n),
+            }),
+        }
+    }
+}
+
+fn process_v6_half(half: &str) -> anyhow::Result<(u128, u8)> {
+    if half.is_empty() {
         // Is empty string
-        return (0, 0);
+        return Ok((0, 0));
     }
     let mut cnt = 0;
     let mut result = 0;
     for seg in half.split(":") {
+        if seg.contains(".") {
+            // Embedded v4 tail, always the last one, counting as two segments
+            let s = parse_v4_addr(seg)?;
+            result = (result << 32) | (s as u128);
+            cnt += 2;
+            continue;
+        }
         let s = u32::from_str_radix(seg, 16).unwrap();
         result = (result << 16) | (s as u128);
         cnt += 1;
     }
-    (result, cnt)
+    Ok((result, cnt))
+}
+
+fn parse_v4_addr(addr: &str) -> anyhow::Result<u32> {
+    let mut collected: u32 = 0;
+    for seg in addr.split(".") {
+        if seg.len() > 3 {
+            return Err(anyhow!("Number too big for v4 segment: {}", seg))
+        }
+        let parsed: u32 = seg.parse().unwrap();
+        if parsed > 255 {
+            return Err(anyhow!("Number too big for v4 segment: {}", seg))
+        }
+        collected = collected << 8 | parsed;
+    }
+    Ok(collected)
+}
+
+// Netmasks take priority, so `0.0.0.0` is /0 and `255.255.255.255` is /32
+// Wildcard masks only when allowed. If both readings are contiguous (0.0.0.0, 255.255.255.255),
+// the one leaving no host bits set in `addr` wins, so the host wildcard `10.0.0.1 0.0.0.0` is a /32,
+// and Cisco's `any`, `0.0.0.0 255.255.255.255`, is a /0
+// Also returns the other reading if it fits `addr` just as well, i.e. `0.0.0.0 0.0.0.0`, to warn about
+fn parse_v4_mask(mask_str: &str, addr: u32, allow_wildcard: bool) -> anyhow::Result<(u8, Option<u8>)> {
+    let mask = parse_v4_addr(mask_str)?;
+    let netmask = (mask.leading_ones() + mask.trailing_zeros() >= 32).then_some(mask.leading_ones());
+    let wildcard = (allow_wildcard && mask.leading_zeros() + mask.trailing_ones() >= 32).then_some(mask.leading_zeros());
+    let host_bits_set = |len: u32| addr & u32::MAX.checked_shr(len).unwrap_or(0) != 0;
+    match (netmask, wildcard) {
+        (Some(32), Some(0)) if addr == 0 => Ok((0, None)),
+        (Some(n), Some(w)) if host_bits_set(n) && !host_bits_set(w) => Ok((w as u8, None)),
+        (Some(n), Some(w)) if !host_bits_set(n) && !host_bits_set(w) => Ok((n as u8, Some(w as u8))),
+        (Some(n), _) => Ok((n as u8, None)),
+        (None, Some(w)) => Ok((w as u8, None)),
+        (None, None) if !allow_wildcard => Err(anyhow!("Non-contiguous netmask: {}", mask_str)),
+        (None, None) => Err(anyhow!("Non-contiguous netmask or wildcard mask: {}", mask_str)),
+    }
+}
+
+// Along with the ignored wildcard reading of an ambiguous mask
+fn parse_v4_cidr(cidr: &str) -> anyhow::Result<(V4, Option<u8>)> {
+    let (addr, len) = cidr.split_once(['/', ' ']).unwrap();
+    let addr = parse_v4_addr(addr)?;
+    let len = len.trim_start();
+    if len.contains(".") {
+        // Only the space separated form accepts wildcard masks
+        let (len_parsed, other) = parse_v4_mask(len, addr, !cidr.contains("/"))?;
+        return Ok((V4(addr, len_parsed), other));
+    }
+    if len.len() > 2 {
+        return Err(anyhow!("Number too big for v4 CIDR length: {}", len))
+    }
+    let len_parsed: u32 = len.parse().unwrap();
+    if len_parsed > 32 {
+        return Err(anyhow!("Number too big for v4 CIDR length: {}", len))
+    }
+    Ok((V4(addr, len_parsed as u8), None))
+}
+
+fn parse_v6_addr(addr_str: &str) -> anyhow::Result<u128> {
+    let mut halves = addr_str.split("::");
+    let first_half = process_v6_half(halves.next().unwrap())?;
+    let second_half = halves.next().map(process_v6_half).transpose()?;
+    if halves.next().is_some() {
+        return Err(anyhow!("IPv6 address containing more than one `::`: {}", addr_str))
+    }
+    let addr = if let Some(second_half) = second_half {
+        if second_half.1 + first_half.1 > 8 {
+            return Err(anyhow!("IPv6 address containing too much specified segments: {}", addr_str))
+        }
+        let first_shifter = 8 - first_half.1;
+        let collected = if first_shifter == 8 {
+            // First segment 0. Don't shift, because <<128 is UB
+            0
+        } else {
+            first_half.0 << (first_shifter as i32 * 16)
+        };
+        collected | second_half.0
+    } else {
+        if first_half.1 != 8 {
+            return Err(anyhow!("IPv6 address containing too little specified segments: {}", addr_str))
+        }
+        first_half.0
+    };
+    Ok(addr)
 }
 
 fn map_expr<'a>(p: Pair<'a, Rule>) -> anyhow::Result<Expr<'a>> {
     // println!("Processing: {:?}", p.as_rule());
     // TODO: a million assertions
     match p.as_rule() {
-        Rule::ident => Ok(Expr::Atomic(Atomic::Ident(p.as_str()))),
-        Rule::v4cidr => {
-            let mut collected: u32 = 0;
-            let mut segs = p.as_str().split("/");
-            let addr = segs.next().unwrap();
-            let len = segs.next().unwrap();
-            for seg in addr.split(".") {
-                if seg.len() > 3 {
-                    return Err(anyhow!("Number too big for v4 segment: {}", seg))
-                }
-                let parsed: u32 = seg.parse().unwrap();
-                if parsed > 256 {
-                    return Err(anyhow!("Number too big for v4 segment: {}", seg))
-                }
-                collected = collected << 8 | parsed;
-            }
-            if len.len() > 2 {
-                return Err(anyhow!("Number too big for v4 CIDR length: {}", len))
-            }
-            let len_parsed: u32 = len.parse().unwrap();
-            if len_parsed > 32 {
-                return Err(anyhow!("Number too big for v4 CIDR length: {}", len))
-            }
-            Ok(Expr::Atomic(Atomic::V4(V4(collected, len_parsed as u8))))
-        },
+        Rule::ident | Rule::qualified_ident => Ok(Expr::Atomic(Atomic::Ident(p.as_str().into()))),
+        Rule::v4cidr => Ok(Expr::Atomic(Atomic::V4(parse_v4_cidr(p.as_str())?.0))),
+        Rule::v4addr => Ok(Expr::Atomic(Atomic::V4(V4(parse_v4_addr(p.as_str())?, 32)))),
+        Rule::int => {
+            let (negative, digits) = match p.as_str().strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, p.as_str()),
+            };
+            let low: u128 = digits.parse().map_err(|_| anyhow!("Number too big: {}", p.as_str()))?;
+            // No negative zero
+            Ok(Expr::Atomic(Atomic::Number(Number { negative: negative && low != 0, carry: false, low })))
+        }
         Rule::v6cidr => {
             let mut split = p.as_str().split("/");
-            let addr_str = split.next().unwrap();
+            let addr = parse_v6_addr(split.next().unwrap())?;
             let len_str = split.next().unwrap();
-
-            let mut halves = addr_str.split("::");
-            let first_half = process_v6_half(halves.next().unwrap());
-            let second_half = halves.next().map(process_v6_half);
-            if halves.next().is_some() {
-                return Err(anyhow!("IPv6 address containing more than one `::`: {}", p.as_str()))
-            }
-            let addr = if let Some(second_half) = second_half {
-                if second_half.1 + first_half.1 > 8 {
-                    return Err(anyhow!("IPv6 address containing too much specified segments: {}", p.as_str()))
-                }
-                let first_shifter = 8 - first_half.1;
-                let collected = if first_shifter == 8 {
-                    // First segment 0. Don't shift, because <<128 is UB
-                    0
-                } else {
-                    first_half.0 << (first_shifter as i32 * 16)
-                };
-                collected | second_half.0
-            } else {
-                if first_half.1 != 8 {
-                    return Err(anyhow!("IPv6 address containing too little specified segments: {}", p.as_str()))
-                }
-                first_half.0
-            };
             if len_str.len() > 3 {
                 return Err(anyhow!("Number too big for v6 CIDR length: {}", len_str))
             }
@@ -120,21 +286,124 @@ fn map_expr<'a>(p: Pair<'a, Rule>) -> anyhow::Result<Expr<'a>> {
 
             Ok(Expr::Atomic(Atomic::V6(V6(addr, len_parsed as u8))))
         }
+        Rule::v6addr => Ok(Expr::Atomic(Atomic::V6(V6(parse_v6_addr(p.as_str())?, 128)))),
+        Rule::v4range => {
+            let mut ends = p.as_str().split("-");
+            let start = parse_v4_addr(ends.next().unwrap())?;
+            let end = parse_v4_addr(ends.next().unwrap())?;
+            if start > end {
+                return Err(anyhow!("Range ending before its start: {}", p.as_str()))
+            }
+            Ok(Expr::Atomic(Atomic::V4Range(start, end)))
+        }
+        Rule::v6range => {
+            let mut ends = p.as_str().split("-");
+            let start = parse_v6_addr(ends.next().unwrap())?;
+            let end = parse_v6_addr(ends.next().unwrap())?;
+            if start > end {
+                return Err(anyhow!("Range ending before its start: {}", p.as_str()))
+            }
+            Ok(Expr::Atomic(Atomic::V6Range(start, end)))
+        }
+        Rule::load => {
+            let mut p = p.into_inner();
+            let path = p.next().unwrap().into_inner().next().unwrap().as_str().into();
+            let family = p.next().map(|f| if f.as_str() == "v4" { Family::V4 } else { Family::V6 });
+            Ok(Expr::Atomic(Atomic::Load { path, family }))
+        }
+        Rule::set_literal => {
+            let mut v4 = Vec::new();
+            let mut v6 = Vec::new();
+            for member in p.into_inner() {
+                let member_str = member.as_str();
+                match map_expr(member)? {
+                    Expr::Atomic(Atomic::V4(v)) => v4.push(v.range()),
+                    Expr::Atomic(Atomic::V6(v)) => v6.push(v.range()),
+                    Expr::Atomic(Atomic::V4Range(start, end)) => v4.push((start, end)),
+                    Expr::Atomic(Atomic::V6Range(start, end)) => v6.push((start, end)),
+                    _ => unreachable!(),
+                }
+                if !v4.is_empty() && !v6.is_empty() {
+                    return Err(anyhow!("Set literal mixing v4 and v6 members: {}", member_str))
+                }
+            }
+            if v4.is_empty() {
+                Ok(Expr::Atomic(Atomic::V6List(v6)))
+            } else {
+                Ok(Expr::Atomic(Atomic::V4List(v4)))
+            }
+        }
         Rule::expr => {
+            let mut p = p.into_inner();
+            let lhs = map_expr(p.next().unwrap())?;
+            let Some(op) = p.next() else { return Ok(lhs) };
+            let rhs = map_expr(p.next().unwrap())?;
+            let op = match op.as_rule() {
+                Rule::contains_op => Predicate::Contains,
+                Rule::subset_op => Predicate::Subset,
+                Rule::overlaps_op => Predicate::Overlaps,
+                Rule::eq_op => Predicate::Equal,
+                _ => Predicate::NotEqual,
+            };
+            Ok(Expr::Predicate(op, Box::new(lhs), Box::new(rhs)))
+        }
+        Rule::sum => {
             let mut p = p.into_inner();
             let mut collected = map_expr(p.next().unwrap())?;
             while let Some(op) = p.next() {
                 let rhs = map_expr(p.next().unwrap())?;
-                if op.as_rule() == Rule::add_op {
-                    collected = Expr::Addition(Box::new(collected), Box::new(rhs));
+                collected = match op.as_rule() {
+                    Rule::add_op => Expr::Addition(Box::new(collected), Box::new(rhs)),
+                    Rule::sub_op => Expr::Subtraction(Box::new(collected), Box::new(rhs)),
+                    _ => Expr::SymmetricDifference(Box::new(collected), Box::new(rhs)),
+                };
+            }
+            Ok(collected)
+        }
+        Rule::term => {
+            let mut p = p.into_inner();
+            let mut collected = map_expr(p.next().unwrap())?;
+            while p.next().is_some() {
+                let rhs = map_expr(p.next().unwrap())?;
+                collected = Expr::Intersection(Box::new(collected), Box::new(rhs));
+            }
+            Ok(collected)
+        }
+        Rule::let_expr => {
+            let mut p = p.into_inner();
+            let ident = p.next().unwrap().as_str().into();
+            let val = Box::new(map_expr(p.next().unwrap())?);
+            let body = Box::new(map_expr(p.nth(1).unwrap())?); // Skipping `in`
+            Ok(Expr::LetIn { ident, val, body })
+        }
+        Rule::call => {
+            let mut p = p.into_inner();
+            let func: Cow<'a, str> = p.next().unwrap().into_inner().next().unwrap().as_str().into();
+            let mut args = Vec::new();
+            let mut named = Vec::new();
+            for arg in p {
+                if arg.as_rule() == Rule::named_arg {
+                    let mut arg = arg.into_inner();
+                    let name = arg.next().unwrap().as_str().into();
+                    named.push((name, map_expr(arg.next().unwrap())?));
+                } else if !named.is_empty() {
+                    return Err(anyhow!("Positional argument after named arguments when calling {}", func));
                 } else {
-                    collected = Expr::Subtraction(Box::new(collected), Box::new(rhs));
+                    args.push(map_expr(arg)?);
                 }
             }
+            Ok(Expr::Call { func, args, named })
+        }
+        Rule::unary => {
+            let mut inner = p.into_inner().rev();
+            let mut collected = map_expr(inner.next().unwrap())?;
+            for _ in inner {
+                collected = Expr::Complement(Box::new(collected));
+            }
             Ok(collected)
         }
         Rule::atomic => map_expr(p.into_inner().next().unwrap()),
-        Rule::paren_expr => map_expr(p.into_inner().skip(1).next().unwrap()),
+        Rule::paren_expr => map_expr(p.into_inner().next().unwrap()),
         e => unreachable!("Excuse me pest? Why am I reading {:?}?", e)
     }
 }
@@ -149,6 +418,23 @@ fn map_stmt<'a>(p: Pair<'a, Rule>) -> anyhow::Result<Stmt<'a>> {
             let val = Box::new(map_expr(p.next().unwrap())?);
             Ok(Stmt::LetIn { ident, val })
         },
+        Rule::fn_def => {
+            let mut p = p.into_inner();
+            let ident = p.next().unwrap().as_str();
+            let params: Vec<&str> = p.next().unwrap().into_inner().map(|e| e.as_str()).collect();
+            // The later one would shadow the earlier, leaving it unreachable
+            if let Some(dup) = params.iter().enumerate().find_map(|(idx, param)| params[..idx].contains(param).then_some(param)) {
+                return Err(anyhow!("Duplicate parameter {} in fn {}", dup, ident));
+            }
+            let body = Box::new(map_expr(p.next().unwrap())?);
+            Ok(Stmt::FnDef { ident, params, body })
+        },
+        Rule::import => {
+            let mut p = p.into_inner();
+            let path = p.next().unwrap().into_inner().next().unwrap().as_str();
+            let alias = p.nth(1).map(|e| e.as_str()); // Skipping `as`
+            Ok(Stmt::Import { path, alias })
+        },
         Rule::expr => {
             map_expr(p).map(Into::into)
         },
@@ -156,13 +442,80 @@ fn map_stmt<'a>(p: Pair<'a, Rule>) -> anyhow::Result<Stmt<'a>> {
     }
 }
 
+// Prefix literals like 10.1.2.3/16 silently drop their host bits, which is most likely a typo
+// Masks readable either way, like `0.0.0.0 0.0.0.0`, are warned about too
+fn check_literals(pairs: Pairs<'_, Rule>, opts: ParseOptions, warnings: &mut Vec<Warning>) -> anyhow::Result<()> {
+    for p in pairs.flatten() {
+        if !matches!(p.as_rule(), Rule::v4cidr | Rule::v6cidr) {
+            continue;
+        }
+
+        if p.as_rule() == Rule::v4cidr {
+            if let (V4(_, len), Some(wildcard)) = parse_v4_cidr(p.as_str())? {
+                let (line, col) = p.line_col();
+                let message = format!("Ambiguous mask in {}, read as netmask /{} rather than wildcard /{}", p.as_str(), len, wildcard);
+                warnings.push(Warning { file: None, pos: Some((line, col)), message });
+            }
+        }
+
+        let masked = match map_expr(p.clone())? {
+            Expr::Atomic(Atomic::V4(v)) if v.range().0 != v.0 => v.to_string(),
+            Expr::Atomic(Atomic::V6(v)) if v.range().0 != v.0 => v.to_string(),
+            _ => continue,
+        };
+        let (line, col) = p.line_col();
+        let message = format!("Host bits set in {}, which is the same as {}", p.as_str(), masked);
+        if opts.strict {
+            return Err(anyhow!("{}:{}: {}", line, col, message));
+        }
+        warnings.push(Warning { file: None, pos: Some((line, col)), message });
+    }
+    Ok(())
+}
+
+// A single line of a list file, as an inclusive range. None for blank and comment-only lines
+pub fn parse_list_entry(line: &str, opts: ParseOptions, warnings: &mut Vec<Warning>) -> anyhow::Result<Option<(Family, u128, u128)>> {
+    let pairs = SrcParser::parse(Rule::list_entry, line)?;
+    check_literals(pairs.clone(), opts, warnings)?;
+    let raw = pairs.into_iter().next().unwrap().into_inner().next().unwrap();
+    if raw.as_rule() == Rule::EOI {
+        return Ok(None);
+    }
+
+    Ok(Some(match map_expr(raw)? {
+        Expr::Atomic(Atomic::V4(v)) => {
+            let (start, end) = v.range();
+            (Family::V4, start as u128, end as u128)
+        }
+        Expr::Atomic(Atomic::V6(v)) => {
+            let (start, end) = v.range();
+            (Family::V6, start, end)
+        }
+        Expr::Atomic(Atomic::V4Range(start, end)) => (Family::V4, start as u128, end as u128),
+        Expr::Atomic(Atomic::V6Range(start, end)) => (Family::V6, start, end),
+        _ => unreachable!(),
+    }))
+}
+
 pub fn parse_single<'a>(input: &'a str) -> anyhow::Result<Stmt<'a>> {
-    let raw = SrcParser::parse(Rule::single_stmt, input)?.next().unwrap().into_inner().next().unwrap();
-    map_stmt(raw)
+    parse_single_with(input, ParseOptions::default(), &mut Vec::new())
 }
 
 pub fn parse<'a>(input: &'a str) -> anyhow::Result<Vec<Stmt<'a>>> {
-    let raw = SrcParser::parse(Rule::multiple_stmt, input)?.next().unwrap();
+    parse_with(input, ParseOptions::default(), &mut Vec::new())
+}
+
+pub fn parse_single_with<'a>(input: &'a str, opts: ParseOptions, warnings: &mut Vec<Warning>) -> anyhow::Result<Stmt<'a>> {
+    let pairs = SrcParser::parse(Rule::single_stmt, input)?;
+    check_literals(pairs.clone(), opts, warnings)?;
+    let raw = pairs.into_iter().next().unwrap().into_inner().next().unwrap();
+    map_stmt(raw)
+}
+
+pub fn parse_with<'a>(input: &'a str, opts: ParseOptions, warnings: &mut Vec<Warning>) -> anyhow::Result<Vec<Stmt<'a>>> {
+    let pairs = SrcParser::parse(Rule::multiple_stmt, input)?;
+    check_literals(pairs.clone(), opts, warnings)?;
+    let raw = pairs.into_iter().next().unwrap();
     raw.into_inner().filter(|e| e.as_rule() != Rule::EOI).map(|p| map_stmt(p)).collect()
 }
 
@@ -196,4 +549,440 @@ fn test_parser() {
     let parsed = parse(example);
     // assert!(parsed.is_ok());
     println!("{:?}", parsed)
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_parser_bare_addr() {
+    let parsed = parse_single("101.6.6.6");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(1694893574u32, 32)))));
+
+    let parsed = parse_single("::1");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(1, 128)))));
+
+    let parsed = parse_single("::");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0, 128)))));
+
+    let parsed = parse_single("1:2:3:4:5:6:7:8");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0x0001_0002_0003_0004_0005_0006_0007_0008, 128)))));
+
+    let parsed = parse_single("beef::1");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0xbeef << 112 | 1, 128)))));
+
+    let parsed = parse_single("beef");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::Ident("beef".into()))));
+
+    let parsed = parse_single("let beef = beef::1 - 101.6.6.6");
+    assert_eq!(parsed.unwrap(), Stmt::LetIn {
+        ident: "beef",
+        val: Box::new(Expr::Subtraction(
+            Box::new(Expr::Atomic(Atomic::V6(V6(0xbeef << 112 | 1, 128)))),
+            Box::new(Expr::Atomic(Atomic::V4(V4(1694893574u32, 32)))),
+        )),
+    });
+
+    assert!(parse_single("1:2:3").is_err());
+    assert!(parse_single("1.2.3.256").is_err());
+    // A number, not an address
+    assert_eq!(parse_single("12").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::Number(12.into()))));
+}
+#[test]
+fn test_parser_range() {
+    let parsed = parse_single("10.0.0.5-10.0.0.77");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4Range(0x0a000005, 0x0a00004d))));
+
+    let parsed = parse_single("2001:db8::1-2001:db8::ff");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6Range(0x20010db8 << 96 | 1, 0x20010db8 << 96 | 0xff))));
+
+    // With spaces, this is a subtraction of two hosts
+    let parsed = parse_single("10.0.0.5 - 10.0.0.77");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Subtraction(
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000005, 32)))),
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x0a00004d, 32)))),
+    )));
+
+    assert!(parse_single("10.0.0.77-10.0.0.5").is_err());
+}
+
+#[test]
+fn test_parser_mask() {
+    let expected = Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x0a000000, 16))));
+    assert_eq!(parse_single("10.0.0.0/255.255.0.0").unwrap(), expected);
+    assert_eq!(parse_single("10.0.0.0 255.255.0.0").unwrap(), expected);
+    assert_eq!(parse_single("10.0.0.0   0.0.255.255").unwrap(), expected);
+
+    assert_eq!(parse_single("0.0.0.0 0.0.0.0").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0, 0)))));
+    assert_eq!(parse_single("1.2.3.4 255.255.255.255").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x01020304, 32)))));
+    // Cisco's `any`, not a single host
+    assert_eq!(parse_single("0.0.0.0 255.255.255.255").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0, 0)))));
+    assert_eq!(parse_single("0.0.0.0/255.255.255.255").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0, 32)))));
+    let mut warnings = Vec::new();
+    parse_single_with("0.0.0.0 0.0.0.0", ParseOptions::default(), &mut warnings).unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].message, "Ambiguous mask in 0.0.0.0 0.0.0.0, read as netmask /0 rather than wildcard /32");
+    let mut warnings = Vec::new();
+    parse_single_with("0.0.0.0 255.255.255.255 + 10.0.0.1 0.0.0.0", ParseOptions::default(), &mut warnings).unwrap();
+    assert!(warnings.is_empty());
+    // Host wildcard, not a /0 netmask
+    assert_eq!(parse_single("10.0.0.1 0.0.0.0").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x0a000001, 32)))));
+    assert!(parse_single_with("10.0.0.1 0.0.0.0", ParseOptions { strict: true }, &mut Vec::new()).is_ok());
+
+    let parsed = parse_single("10.0.0.0 255.0.0.0 - 10.0.0.0/0.0.0.0");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Subtraction(
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))),
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000000, 0)))),
+    )));
+
+    let err = parse_single("10.0.0.0/255.0.255.0").unwrap_err();
+    assert!(err.to_string().contains("Non-contiguous"));
+    assert!(parse_single("10.0.0.0/0.0.255.255").is_err());
+    assert!(parse_single("10.0.0.0 0.255.0.255").is_err());
+
+    // Masks don't span lines, so these are two statements
+    let parsed = parse("10.0.0.1\n10.0.0.2").unwrap();
+    assert_eq!(parsed.len(), 2);
+}
+
+#[test]
+fn test_parser_v6_v4_tail() {
+    let parsed = parse_single("::ffff:192.0.2.0/120");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0xffff_c000_0200, 120)))));
+
+    let parsed = parse_single("64:ff9b::198.51.100.0/120");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0x0064_ff9b << 96 | 0xc633_6400, 120)))));
+
+    let parsed = parse_single("::1.2.3.4");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0x0102_0304, 128)))));
+
+    let parsed = parse_single("1:2:3:4:5:6:1.2.3.4");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0x0001_0002_0003_0004_0005_0006_0102_0304, 128)))));
+
+    let parsed = parse_single("1:2:3:4:5::1.2.3.4");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(0x0001_0002_0003_0004_0005_0000_0102_0304, 128)))));
+
+    let parsed = parse_single("::ffff:10.0.0.1-::ffff:10.0.0.9");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6Range(0xffff_0a00_0001, 0xffff_0a00_0009))));
+
+    // Too many segments once the v4 tail is counted as two
+    assert!(parse_single("1:2:3:4:5:6:7::1.2.3.4").is_err());
+    assert!(parse_single("1:2:3:4:5:6:7:1.2.3.4").is_err());
+    assert!(parse_single("::ffff:1.2.3.256").is_err());
+}
+
+#[test]
+fn test_parser_comments() {
+    let example = r#"
+    # Whole-line comment
+    let meow = ::/0 // Trailing comment
+    /* Block comment
+       spanning lines */
+    meow - /* inline */ 2001:da8::/32
+    101.6.6.6/32
+    + 0.0.0.0/2 # Why this is here
+    // + 64.0.0.0/3
+    + 96.0.0.0/6
+    "#;
+    let parsed = parse(example).unwrap();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[2], Stmt::Expr(Expr::Addition(
+        Box::new(Expr::Addition(
+            Box::new(Expr::Atomic(Atomic::V4(V4(1694893574u32, 32)))),
+            Box::new(Expr::Atomic(Atomic::V4(V4(0, 2)))),
+        )),
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x60000000, 6)))),
+    )));
+
+    let parsed = parse_single("10.0.0.0/8 # comment");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))));
+
+    let parsed = parse_single("/* only */ 10.0.0.0/8 // comment");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))));
+
+    assert!(parse_single("/* unterminated 10.0.0.0/8").is_err());
+}
+
+#[test]
+fn test_parser_set_literal() {
+    let parsed = parse_single("{1.2.3.0/24, 5.6.7.8, 10.0.0.1-10.0.0.9}");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4List(vec![
+        (0x01020300, 0x010203ff),
+        (0x05060708, 0x05060708),
+        (0x0a000001, 0x0a000009),
+    ]))));
+
+    let parsed = parse_single("{\n  ::/0, # Everything\n  ::1,\n}");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6List(vec![
+        (0, u128::MAX),
+        (1, 1),
+    ]))));
+
+    let err = parse_single("{1.2.3.0/24, ::1}").unwrap_err();
+    assert!(err.to_string().contains("mixing v4 and v6"));
+    assert!(parse_single("{}").is_err());
+    assert!(parse_single("{a, b}").is_err());
+}
+
+#[test]
+fn test_parser_let_expr() {
+    let parsed = parse_single("let a = 10.0.0.0/8 in a - 10.1.0.0/16");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::LetIn {
+        ident: "a".into(),
+        val: Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))),
+        body: Box::new(Expr::Subtraction(
+            Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+            Box::new(Expr::Atomic(Atomic::V4(V4(0x0a010000, 16)))),
+        )),
+    }));
+
+    let parsed = parse_single("let b = let a = ::/0 in a");
+    assert_eq!(parsed.unwrap(), Stmt::LetIn {
+        ident: "b",
+        val: Box::new(Expr::LetIn {
+            ident: "a".into(),
+            val: Box::new(Expr::Atomic(Atomic::V6(V6(0, 0)))),
+            body: Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+        }),
+    });
+
+    let parsed = parse("let a = ::/0\nlet b = a in b\nlet c = a").unwrap();
+    assert_eq!(parsed.len(), 3);
+    assert!(matches!(parsed[1], Stmt::Expr(Expr::LetIn { .. })));
+    assert!(parse_single("let in = ::/0 in in").is_err());
+
+    // Identifiers starting with `in` on the next line
+    let parsed = parse("let a = 10.0.0.0/8\ninterior(a, 16)\nindex").unwrap();
+    assert_eq!(parsed, vec![
+        Stmt::LetIn { ident: "a", val: Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))) },
+        Stmt::Expr(Expr::Call {
+            func: "interior".into(),
+            args: vec![Expr::Atomic(Atomic::Ident("a".into())), Expr::Atomic(Atomic::Number(16.into()))],
+            named: vec![],
+        }),
+        Stmt::Expr(Expr::Atomic(Atomic::Ident("index".into()))),
+    ]);
+    let parsed = parse("let a = 10.0.0.0/8\nin_use").unwrap();
+    assert_eq!(parsed[1], Stmt::Expr(Expr::Atomic(Atomic::Ident("in_use".into()))));
+}
+
+#[test]
+fn test_parser_fn() {
+    let parsed = parse_single("fn split_tunnel(x) = !bogons - x");
+    assert_eq!(parsed.unwrap(), Stmt::FnDef {
+        ident: "split_tunnel",
+        params: vec!["x"],
+        body: Box::new(Expr::Subtraction(
+            Box::new(Expr::Complement(Box::new(Expr::Atomic(Atomic::Ident("bogons".into()))))),
+            Box::new(Expr::Atomic(Atomic::Ident("x".into()))),
+        )),
+    });
+
+    let parsed = parse_single("fn univ() = ::/0");
+    assert_eq!(parsed.unwrap(), Stmt::FnDef {
+        ident: "univ",
+        params: vec![],
+        body: Box::new(Expr::Atomic(Atomic::V6(V6(0, 0)))),
+    });
+
+    let parsed = parse_single("f(a, 10.0.0.0/8 + b) & g()");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Intersection(
+        Box::new(Expr::Call {
+            func: "f".into(),
+            args: vec![
+                Expr::Atomic(Atomic::Ident("a".into())),
+                Expr::Addition(
+                    Box::new(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))),
+                    Box::new(Expr::Atomic(Atomic::Ident("b".into()))),
+                ),
+            ],
+            named: vec![],
+        }),
+        Box::new(Expr::Call { func: "g".into(), args: vec![], named: vec![] }),
+    )));
+
+    // A parenthesized expression on the next line is not a call
+    let parsed = parse("a\n(b)").unwrap();
+    assert_eq!(parsed.len(), 2);
+
+    let parsed = parse_single("summarize(a + b, max_prefixes = 8)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Call {
+        func: "summarize".into(),
+        args: vec![Expr::Addition(
+            Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+            Box::new(Expr::Atomic(Atomic::Ident("b".into()))),
+        )],
+        named: vec![("max_prefixes".into(), Expr::Atomic(Atomic::Number(8.into())))],
+    }));
+
+    // Comparison, not a named argument
+    let parsed = parse_single("f(a == b)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Call {
+        func: "f".into(),
+        args: vec![Expr::Predicate(
+            Predicate::Equal,
+            Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+            Box::new(Expr::Atomic(Atomic::Ident("b".into()))),
+        )],
+        named: vec![],
+    }));
+
+    assert!(parse_single("f(x = a, b)").is_err());
+    assert!(parse_single("fn fn(x) = x").is_err());
+    assert!(parse_single("fn f(x, ) = x").is_err());
+    let err = parse_single("fn f(x, y, x) = x").unwrap_err();
+    assert_eq!(err.to_string(), "Duplicate parameter x in fn f");
+    assert!(parse("fn f(x, x) = x\nf(1.2.3.4, 5.6.7.8)").is_err());
+}
+
+#[test]
+fn test_parser_import() {
+    let parsed = parse_single("import \"common.cidr\"");
+    assert_eq!(parsed.unwrap(), Stmt::Import { path: "common.cidr", alias: None });
+
+    let parsed = parse_single("import \"../shared/common # v2.cidr\" as corp");
+    assert_eq!(parsed.unwrap(), Stmt::Import { path: "../shared/common # v2.cidr", alias: Some("corp") });
+
+    let parsed = parse_single("corp.office - corp.tunnel(10.0.0.0/8)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Subtraction(
+        Box::new(Expr::Atomic(Atomic::Ident("corp.office".into()))),
+        Box::new(Expr::Call {
+            func: "corp.tunnel".into(),
+            args: vec![Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))],
+            named: vec![],
+        }),
+    )));
+
+    // Identifiers starting with `as` on the next line
+    let parsed = parse("import \"common.cidr\"\nassets\nassets + 1.1.1.1").unwrap();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[0], Stmt::Import { path: "common.cidr", alias: None });
+    assert_eq!(parsed[1], Stmt::Expr(Expr::Atomic(Atomic::Ident("assets".into()))));
+
+    assert!(parse_single("import common").is_err());
+    assert!(parse_single("import \"a\" as 1.2.3.4").is_err());
+    assert!(parse_single("let as = ::/0").is_err());
+}
+
+#[test]
+fn test_parser_load() {
+    let parsed = parse_single("load(\"cn.txt\") - load(\"mixed.txt\", v6)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Subtraction(
+        Box::new(Expr::Atomic(Atomic::Load { path: "cn.txt".into(), family: None })),
+        Box::new(Expr::Atomic(Atomic::Load { path: "mixed.txt".into(), family: Some(Family::V6) })),
+    )));
+    assert!(parse_single("load(cn)").is_err());
+    assert!(parse_single("load(\"cn.txt\", v5)").is_err());
+
+    let entry = |line| parse_list_entry(line, ParseOptions::default(), &mut Vec::new());
+    assert_eq!(entry("  10.0.0.0/8  ").unwrap(), Some((Family::V4, 0x0a000000, 0x0affffff)));
+    assert_eq!(entry("::1 # loopback").unwrap(), Some((Family::V6, 1, 1)));
+    assert_eq!(entry("10.0.0.0 255.0.0.0").unwrap(), Some((Family::V4, 0x0a000000, 0x0affffff)));
+    assert_eq!(entry("").unwrap(), None);
+    assert_eq!(entry("// comment").unwrap(), None);
+    assert!(entry("10.0.0.0/8 10.0.0.0/8").is_err());
+    assert!(entry("meow").is_err());
+}
+
+#[test]
+fn test_parser_strict() {
+    let strict = ParseOptions { strict: true };
+
+    let mut warnings = Vec::new();
+    let parsed = parse_with("let a = 10.0.0.0/8\na - {10.1.2.3/16, 10.2.0.0/16}\n2001:db8::1/32", ParseOptions::default(), &mut warnings);
+    assert_eq!(parsed.unwrap().len(), 3);
+    assert_eq!(warnings, vec![
+        Warning {
+            file: None,
+            pos: Some((2, 6)),
+            message: "Host bits set in 10.1.2.3/16, which is the same as 10.1.0.0/16".to_owned(),
+        },
+        Warning {
+            file: None,
+            pos: Some((3, 1)),
+            message: "Host bits set in 2001:db8::1/32, which is the same as 2001:db8::/32".to_owned(),
+        },
+    ]);
+    assert_eq!(warnings[0].to_string(), "2:6: Host bits set in 10.1.2.3/16, which is the same as 10.1.0.0/16");
+
+    let err = parse_single_with("0.0.0.0/0 - 10.1.2.3 255.255.0.0", strict, &mut Vec::new()).unwrap_err();
+    assert_eq!(err.to_string(), "1:13: Host bits set in 10.1.2.3 255.255.0.0, which is the same as 10.1.0.0/16");
+    assert!(parse_list_entry("10.1.2.3/16", strict, &mut Vec::new()).is_err());
+
+    // Bare addresses and ranges never have host bits
+    let mut warnings = Vec::new();
+    assert!(parse_single_with("10.1.2.3 + 10.1.2.3-10.1.2.9 + 10.1.0.0/16 + ::1/128", strict, &mut warnings).is_ok());
+    assert!(warnings.is_empty());
+}
+
+#[test]
+fn test_parser_predicate() {
+    let parsed = parse_single("a + b contains 10.2.0.0/16");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Predicate(
+        Predicate::Contains,
+        Box::new(Expr::Addition(
+            Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+            Box::new(Expr::Atomic(Atomic::Ident("b".into()))),
+        )),
+        Box::new(Expr::Atomic(Atomic::V4(V4(0x0a020000, 16)))),
+    )));
+
+    let parsed = parse_single("let c = a != !b");
+    assert_eq!(parsed.unwrap(), Stmt::LetIn {
+        ident: "c",
+        val: Box::new(Expr::Predicate(
+            Predicate::NotEqual,
+            Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+            Box::new(Expr::Complement(Box::new(Expr::Atomic(Atomic::Ident("b".into()))))),
+        )),
+    });
+
+    // Identifiers merely starting with an operator keyword
+    let parsed = parse_single("subsets overlaps containsx");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Predicate(
+        Predicate::Overlaps,
+        Box::new(Expr::Atomic(Atomic::Ident("subsets".into()))),
+        Box::new(Expr::Atomic(Atomic::Ident("containsx".into()))),
+    )));
+
+    assert!(parse_single("a == b == c").is_err());
+    assert!(parse_single("let subset = a").is_err());
+}
+
+#[test]
+fn test_parser_number() {
+    let parsed = parse_single("filter_len(x, 8, 24)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Call {
+        func: "filter_len".into(),
+        args: vec![
+            Expr::Atomic(Atomic::Ident("x".into())),
+            Expr::Atomic(Atomic::Number(8.into())),
+            Expr::Atomic(Atomic::Number(24.into())),
+        ],
+        named: vec![],
+    }));
+
+    // Addresses starting with digits are still addresses
+    assert_eq!(parse_single("1::").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V6(V6(1 << 112, 128)))));
+    assert_eq!(parse_single("10.0.0.0/8").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::V4(V4(0x0a000000, 8)))));
+
+    assert_eq!(
+        parse_single("340282366920938463463374607431768211455").unwrap(),
+        Stmt::Expr(Expr::Atomic(Atomic::Number(u128::MAX.into()))),
+    );
+    assert!(parse_single("340282366920938463463374607431768211456").is_err());
+
+    let parsed = parse_single("offset(p, -5)");
+    assert_eq!(parsed.unwrap(), Stmt::Expr(Expr::Call {
+        func: "offset".into(),
+        args: vec![
+            Expr::Atomic(Atomic::Ident("p".into())),
+            Expr::Atomic(Atomic::Number(Number { negative: true, carry: false, low: 5 })),
+        ],
+        named: vec![],
+    }));
+    assert_eq!(parse_single("-0").unwrap(), Stmt::Expr(Expr::Atomic(Atomic::Number(0.into()))));
+    // Still a subtraction
+    assert_eq!(parse_single("a -1").unwrap(), Stmt::Expr(Expr::Subtraction(
+        Box::new(Expr::Atomic(Atomic::Ident("a".into()))),
+        Box::new(Expr::Atomic(Atomic::Number(1.into()))),
+    )));
+    assert!(parse_single("10.0").is_err());
+}